@@ -0,0 +1,312 @@
+use chrono::{DateTime, Utc};
+use coap_lite::{CoapOption, Packet};
+
+/// Conditional attributes of an Observe registration, given as Uri-Query parameters of the registration
+/// (draft-ietf-core-conditional-attributes), e.g. `?pmax=60&st=0.5`.
+///
+/// Periods are in seconds. Thresholds and steps are compared against the data read as a number.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConditionalAttributes {
+    /// Minimum period between two notifications.
+    pmin: Option<f64>,
+    /// Maximum period between two notifications, the latest data is sent again once it has passed.
+    pmax: Option<f64>,
+    /// Upper threshold, a value crossing it in either direction is notified.
+    gt: Option<f64>,
+    /// Lower threshold, a value crossing it in either direction is notified.
+    lt: Option<f64>,
+    /// Step, a value that moved at least this much from the last notified value is notified.
+    st: Option<f64>,
+    /// Minimum period between two evaluations of the conditions.
+    epmin: Option<f64>,
+    /// Maximum period between two evaluations of the conditions.
+    epmax: Option<f64>,
+}
+
+impl ConditionalAttributes {
+    /// Whether any of gt, lt and st is set. Without them every change of the data is notified.
+    fn has_value_conditions(&self) -> bool {
+        self.gt.is_some() || self.lt.is_some() || self.st.is_some()
+    }
+}
+
+/// Parses the conditional attributes from the Uri-Query options of an Observe registration.
+/// Other queries are left to the caller. A repeated attribute takes the last value.
+pub fn parse_conditional_attributes(packet: &Packet) -> Result<ConditionalAttributes, String> {
+    let mut attributes = ConditionalAttributes::default();
+    let Some(queries) = packet.get_option(CoapOption::UriQuery) else {
+        return Ok(attributes);
+    };
+    for query in queries {
+        let query = std::str::from_utf8(query).map_err(|_| "Uri-Query is not valid utf-8".to_string())?;
+        let (name, value) = query.split_once('=').unwrap_or((query, ""));
+        let attribute = match name {
+            "pmin" => &mut attributes.pmin,
+            "pmax" => &mut attributes.pmax,
+            "gt" => &mut attributes.gt,
+            "lt" => &mut attributes.lt,
+            "st" => &mut attributes.st,
+            "epmin" => &mut attributes.epmin,
+            "epmax" => &mut attributes.epmax,
+            _ => continue,
+        };
+        let number = value.parse::<f64>().ok()
+            .filter(|number| number.is_finite())
+            .ok_or_else(|| format!("{} must be a number, got '{}'", name, value))?;
+        if name != "gt" && name != "lt" && number < 0.0 {
+            return Err(format!("{} must not be negative", name));
+        }
+        if name == "st" && number == 0.0 {
+            return Err("st must be greater than 0".to_string());
+        }
+        *attribute = Some(number);
+    }
+
+    if let (Some(pmin), Some(pmax)) = (attributes.pmin, attributes.pmax) {
+        if pmax <= pmin {
+            return Err("pmax must be greater than pmin".to_string());
+        }
+    }
+    if let (Some(epmin), Some(epmax)) = (attributes.epmin, attributes.epmax) {
+        if epmax <= epmin {
+            return Err("epmax must be greater than epmin".to_string());
+        }
+    }
+    Ok(attributes)
+}
+
+/// Reads topic data as a number for the value conditions, e.g. `21.5`. Data that isn't one gives None.
+pub fn numeric_value(data: &[u8]) -> Option<f64> {
+    std::str::from_utf8(data).ok()?
+        .trim()
+        .parse::<f64>().ok()
+        .filter(|number| number.is_finite())
+}
+
+/// Seconds from an earlier moment to now.
+fn seconds_since(moment: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+    (now - moment).num_milliseconds() as f64 / 1000.0
+}
+
+/// The conditional attributes of an observation, with the state of the observation they are evaluated against.
+///
+/// New data is evaluated when it is published, unless epmin hasn't passed since the last evaluation. It is then
+/// evaluated once epmin has passed, and at the latest every epmax. Data that meets the conditions within pmin
+/// of the last notification is sent once pmin has passed, and the latest data is sent again every pmax.
+#[derive(Debug, Clone)]
+pub struct Conditions {
+    attributes: ConditionalAttributes,
+    /// When the subscriber was last notified, the registration response counts as a notification.
+    last_notification: DateTime<Utc>,
+    /// The data of the last notification as a number, if it was one.
+    last_value: Option<f64>,
+    /// When the conditions were last evaluated.
+    last_evaluation: DateTime<Utc>,
+    /// Data was published within epmin of the last evaluation and is yet to be evaluated.
+    unevaluated: bool,
+    /// Data met the conditions within pmin of the last notification and is to be sent once pmin has passed.
+    pending: bool,
+}
+
+impl Conditions {
+    /// Conditions of an observation whose registration response carried the given data.
+    pub fn new(attributes: ConditionalAttributes, data: &[u8], now: DateTime<Utc>) -> Self {
+        Conditions {
+            attributes,
+            last_notification: now,
+            last_value: numeric_value(data),
+            last_evaluation: now,
+            unevaluated: false,
+            pending: false,
+        }
+    }
+
+    /// Whether the observation has conditional attributes at all, without them every publication is notified.
+    pub fn is_conditional(&self) -> bool {
+        self.attributes != ConditionalAttributes::default()
+    }
+
+    /// Takes in newly published data. Returns whether the subscriber is to be notified of it right away.
+    pub fn on_publish(&mut self, data: &[u8], now: DateTime<Utc>) -> bool {
+        if seconds_since(self.last_evaluation, now) < self.attributes.epmin.unwrap_or(0.0) {
+            self.unevaluated = true;
+            return false;
+        }
+        self.evaluate(numeric_value(data), true, now)
+    }
+
+    /// Checks the timed conditions against the latest data, called periodically.
+    /// Returns whether the subscriber is to be notified of the latest data now.
+    pub fn on_tick(&mut self, data: &[u8], now: DateTime<Utc>) -> bool {
+        let value = numeric_value(data);
+        let since_evaluation = seconds_since(self.last_evaluation, now);
+        if self.unevaluated && since_evaluation >= self.attributes.epmin.unwrap_or(0.0) && self.evaluate(value, true, now) {
+            return true;
+        }
+        // Re-evaluation of data that didn't change can only meet the value conditions
+        if self.attributes.epmax.is_some_and(|epmax| since_evaluation >= epmax) && self.evaluate(value, false, now) {
+            return true;
+        }
+
+        let since_notification = seconds_since(self.last_notification, now);
+        (self.pending && since_notification >= self.attributes.pmin.unwrap_or(0.0))
+            || self.attributes.pmax.is_some_and(|pmax| since_notification >= pmax)
+    }
+
+    /// Records that the subscriber was notified of the data.
+    pub fn notified(&mut self, data: &[u8], now: DateTime<Utc>) {
+        self.last_notification = now;
+        self.last_value = numeric_value(data);
+        self.pending = false;
+    }
+
+    /// Evaluates the value conditions and pmin. Returns whether the subscriber is to be notified right away,
+    /// a value that meets the conditions before pmin has passed is held back until it has.
+    fn evaluate(&mut self, value: Option<f64>, changed: bool, now: DateTime<Utc>) -> bool {
+        self.last_evaluation = now;
+        self.unevaluated = false;
+        if !self.is_reportable(value, changed) {
+            return false;
+        }
+        if seconds_since(self.last_notification, now) >= self.attributes.pmin.unwrap_or(0.0) {
+            return true;
+        }
+        self.pending = true;
+        false
+    }
+
+    /// Checks a value against gt, lt and st, compared to the last notified value.
+    /// Without those conditions, or when either value isn't a number, every change is reportable.
+    fn is_reportable(&self, value: Option<f64>, changed: bool) -> bool {
+        let attributes = &self.attributes;
+        let (Some(last), Some(value)) = (self.last_value, value) else {
+            return changed;
+        };
+        if !attributes.has_value_conditions() {
+            return changed;
+        }
+        attributes.gt.is_some_and(|gt| (last > gt) != (value > gt))
+            || attributes.lt.is_some_and(|lt| (last < lt) != (value < lt))
+            || attributes.st.is_some_and(|st| (value - last).abs() >= st)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// Parses the conditional attributes from the queries of a registration.
+    fn attributes(queries: &[&str]) -> Result<ConditionalAttributes, String> {
+        let mut packet = Packet::new();
+        for query in queries {
+            packet.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        }
+        parse_conditional_attributes(&packet)
+    }
+
+    /// The moment the given number of seconds after the registration at start.
+    fn at(start: DateTime<Utc>, seconds: f64) -> DateTime<Utc> {
+        start + Duration::milliseconds((seconds * 1000.0) as i64)
+    }
+
+    #[test]
+    fn test_parse_conditional_attributes() {
+        let parsed = attributes(&["pmin=1", "pmax=60", "gt=25.5", "lt=-10", "st=0.5", "epmin=2", "epmax=30", "con", "pmin=5"]).unwrap();
+        assert_eq!(parsed, ConditionalAttributes {
+            pmin: Some(5.0), pmax: Some(60.0), gt: Some(25.5), lt: Some(-10.0), st: Some(0.5), epmin: Some(2.0), epmax: Some(30.0),
+        });
+        assert_eq!(attributes(&[]).unwrap(), ConditionalAttributes::default());
+
+        for queries in [&["pmin"][..], &["st=abc"], &["gt=inf"], &["pmax=-1"], &["st=0"], &["pmin=5", "pmax=5"], &["epmin=3", "epmax=2"]] {
+            assert!(attributes(queries).is_err(), "{:?} was accepted", queries);
+        }
+    }
+
+    #[test]
+    fn test_numeric_value() {
+        assert_eq!(numeric_value(b" 21.5\n"), Some(21.5));
+        assert_eq!(numeric_value(b"-3"), Some(-3.0));
+        assert_eq!(numeric_value(b"NaN"), None);
+        assert_eq!(numeric_value(b"{\"t\": 21.5}"), None);
+        assert_eq!(numeric_value(&[0xff]), None);
+    }
+
+    #[test]
+    fn test_step_and_thresholds_are_reportable() {
+        let start = Utc::now();
+        let mut conditions = Conditions::new(attributes(&["st=1", "gt=30"]).unwrap(), b"20", start);
+        assert!(conditions.is_conditional());
+        assert!(!conditions.on_publish(b"20.5", start));
+        assert!(conditions.on_publish(b"21", start));
+        conditions.notified(b"21", start);
+        // Measured from the last notified value, not the last published one
+        assert!(!conditions.on_publish(b"20.5", start));
+        assert!(!conditions.on_publish(b"21.9", start));
+        assert!(conditions.on_publish(b"20", start));
+
+        // Crossing a threshold is reportable however small the step
+        let mut conditions = Conditions::new(attributes(&["st=100", "gt=30"]).unwrap(), b"29.9", start);
+        assert!(conditions.on_publish(b"30.1", start));
+        conditions.notified(b"30.1", start);
+        assert!(!conditions.on_publish(b"40", start));
+        assert!(conditions.on_publish(b"29", start));
+    }
+
+    #[test]
+    fn test_data_that_isnt_a_number_is_reportable_on_change() {
+        let start = Utc::now();
+        let mut conditions = Conditions::new(attributes(&["st=1"]).unwrap(), b"open", start);
+        assert!(conditions.on_publish(b"closed", start));
+        // Re-evaluation without a change isn't
+        let mut conditions = Conditions::new(attributes(&["epmax=1"]).unwrap(), b"open", start);
+        assert!(!conditions.on_tick(b"open", at(start, 1.0)));
+
+        let conditions = Conditions::new(ConditionalAttributes::default(), b"open", start);
+        assert!(!conditions.is_conditional());
+    }
+
+    #[test]
+    fn test_pmin_holds_back_until_tick() {
+        let start = Utc::now();
+        let mut conditions = Conditions::new(attributes(&["pmin=2"]).unwrap(), b"0", start);
+        assert!(!conditions.on_publish(b"1", at(start, 0.5)));
+        assert!(!conditions.on_tick(b"1", at(start, 1.5)));
+        assert!(conditions.on_tick(b"1", at(start, 2.0)));
+        conditions.notified(b"1", at(start, 2.0));
+        assert!(!conditions.on_tick(b"1", at(start, 5.0)));
+        assert!(conditions.on_publish(b"2", at(start, 5.0)));
+    }
+
+    #[test]
+    fn test_pmax_resends_on_tick() {
+        let start = Utc::now();
+        let mut conditions = Conditions::new(attributes(&["pmax=10", "st=5"]).unwrap(), b"0", start);
+        assert!(!conditions.on_tick(b"0", at(start, 9.5)));
+        assert!(conditions.on_tick(b"0", at(start, 10.0)));
+        conditions.notified(b"0", at(start, 10.0));
+        assert!(!conditions.on_tick(b"0", at(start, 15.0)));
+        assert!(conditions.on_tick(b"0", at(start, 20.0)));
+    }
+
+    #[test]
+    fn test_epmin_defers_and_epmax_forces_evaluation() {
+        let start = Utc::now();
+        let mut conditions = Conditions::new(attributes(&["epmin=2", "st=5"]).unwrap(), b"0", start);
+        assert!(!conditions.on_publish(b"10", at(start, 1.0)));
+        // Evaluated against the latest data once epmin has passed
+        assert!(!conditions.on_tick(b"3", at(start, 2.0)));
+        assert!(!conditions.on_tick(b"10", at(start, 3.0)));
+
+        let mut conditions = Conditions::new(attributes(&["epmin=2", "st=5"]).unwrap(), b"0", start);
+        assert!(!conditions.on_publish(b"10", at(start, 1.0)));
+        assert!(conditions.on_tick(b"10", at(start, 2.0)));
+
+        // epmax evaluates the latest data against the value conditions even without a publication
+        let mut conditions = Conditions::new(attributes(&["epmax=5", "lt=0"]).unwrap(), b"1", start);
+        assert!(!conditions.on_tick(b"-1", at(start, 4.0)));
+        assert!(conditions.on_tick(b"-1", at(start, 5.0)));
+        conditions.notified(b"-1", at(start, 5.0));
+        assert!(!conditions.on_tick(b"-1", at(start, 10.0)));
+    }
+}
@@ -0,0 +1,99 @@
+use coap_lite::{MessageType, Packet};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// Initial time to wait for an acknowledgement, ACK_TIMEOUT in RFC 7252.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Random factor applied to the initial timeout, ACK_RANDOM_FACTOR in RFC 7252.
+pub const ACK_RANDOM_FACTOR: f64 = 1.5;
+/// Number of retransmissions before giving up, MAX_RETRANSMIT in RFC 7252.
+pub const MAX_RETRANSMIT: u32 = 4;
+
+lazy_static! {
+    /// Confirmable messages waiting for an answer, by receiver and message id.
+    /// The listeners hand the answers over with answer().
+    static ref PENDING: Mutex<HashMap<(SocketAddr, u16), oneshot::Sender<MessageType>>> = Mutex::new(HashMap::new());
+}
+
+/// Outcome of sending a confirmable message.
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    /// The receiver acknowledged the message.
+    Acknowledged,
+    /// The receiver rejected the message with a reset.
+    Reset,
+    /// No acknowledgement arrived, even after MAX_RETRANSMIT retransmissions.
+    TimedOut,
+}
+
+/// Picks the initial timeout randomly between ACK_TIMEOUT and ACK_TIMEOUT * ACK_RANDOM_FACTOR.
+fn initial_timeout() -> Duration {
+    ACK_TIMEOUT.mul_f64(rand::thread_rng().gen_range(1.0..ACK_RANDOM_FACTOR))
+}
+
+/// Sends the packet to addr from the given socket as a confirmable message with a new message id.
+///
+/// The message is retransmitted with exponentially growing timeouts (RFC 7252 section 4.2)
+/// until the receiver acknowledges or resets it, or MAX_RETRANSMIT retransmissions have been made.
+/// The answer arrives on the socket's listener, which passes it on with answer().
+pub async fn send_confirmable(socket: &UdpSocket, addr: SocketAddr, mut packet: Packet) -> std::io::Result<Delivery> {
+    packet.header.set_type(MessageType::Confirmable);
+    let (answer_sender, mut answer_receiver) = oneshot::channel();
+    let key = {
+        let mut pending = PENDING.lock().unwrap();
+        let mut key = (addr, rand::thread_rng().gen());
+        while pending.contains_key(&key) {
+            key.1 = rand::thread_rng().gen();
+        }
+        pending.insert(key, answer_sender);
+        key
+    };
+    packet.header.message_id = key.1;
+
+    let delivery = async {
+        let bytes = packet.to_bytes()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        let mut timeout = initial_timeout();
+        for _ in 0..=MAX_RETRANSMIT {
+            socket.send_to(&bytes, addr).await?;
+            if let Ok(answer) = tokio::time::timeout(timeout, &mut answer_receiver).await {
+                return Ok(match answer {
+                    Ok(MessageType::Acknowledgement) => Delivery::Acknowledged,
+                    _ => Delivery::Reset,
+                });
+            }
+            timeout *= 2;
+        }
+        Ok(Delivery::TimedOut)
+    }.await;
+
+    // An answer already took the entry out, and the key may have been given to another message since
+    answer_receiver.close();
+    let mut pending = PENDING.lock().unwrap();
+    if pending.get(&key).is_some_and(oneshot::Sender::is_closed) {
+        pending.remove(&key);
+    }
+    delivery
+}
+
+/// Passes an acknowledgement or reset from src to the confirmable message it answers.
+/// Returns false if the packet doesn't answer any message waiting for one.
+pub fn answer(src: SocketAddr, packet: &Packet) -> bool {
+    let answer_type = packet.header.get_type();
+    if !matches!(answer_type, MessageType::Acknowledgement | MessageType::Reset) {
+        return false;
+    }
+    match PENDING.lock().unwrap().remove(&(src, packet.header.message_id)) {
+        Some(answer_sender) => {
+            let _ = answer_sender.send(answer_type);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,13 @@
+//! Content-Format numbers of the payloads the broker exchanges.
+//!
+//! The clients in this repository include this file too, so there is a single place for the numbers.
+
+/// Content-Format of application/json.
+pub const JSON_CONTENT_FORMAT: u16 = 50;
+/// Content-Format of application/merge-patch+json, accepted as json for PATCH and iPATCH.
+pub const MERGE_PATCH_JSON_CONTENT_FORMAT: u16 = 52;
+/// Content-Format of application/core-pubsub+cbor.
+///
+/// This is a placeholder: the draft leaves the number to IANA (TBD), and 65000 is taken from the
+/// experimental range (RFC 7252 section 12.3) until one is assigned. Change it here once that happens.
+pub const CORE_PUBSUB_CBOR_CONTENT_FORMAT: u16 = 65000;
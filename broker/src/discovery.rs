@@ -0,0 +1,276 @@
+use coap_lite::link_format::LinkFormatWrite;
+use coap_lite::{CoapOption, Packet};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// Link attributes whose value is a space-separated list, each value can match a query filter on its own (RFC 6690 section 4.1).
+const MULTI_VALUE_ATTRIBUTES: [&str; 4] = ["rt", "if", "rel", "ct"];
+
+/// Link attributes that are always written as a quoted-string (RFC 6690 section 2).
+const QUOTED_ATTRIBUTES: [&str; 1] = ["title"];
+
+/// A link to a resource hosted by the broker, served in link-format by /.well-known/core.
+#[derive(Debug, Clone)]
+pub struct Link {
+    href: String,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Link {
+    pub fn new(href: impl Into<String>) -> Self {
+        Link { href: href.into(), attributes: Vec::new() }
+    }
+
+    /// Adds a target attribute to the link, attributes are written in the order they were added.
+    pub fn attr(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.attributes.push((key, value.into()));
+        self
+    }
+
+    /// Checks the link against a single query filter. The filter name is either href or an attribute name,
+    /// and a value ending in `*` matches every value starting with the part before it.
+    fn matches(&self, filter: &QueryFilter) -> bool {
+        if filter.name == "href" {
+            return filter.matches_value(&self.href);
+        }
+        self.attributes.iter()
+            .filter(|(key, _)| *key == filter.name)
+            .any(|(key, value)| {
+                if MULTI_VALUE_ATTRIBUTES.contains(key) {
+                    value.split(' ').any(|value| filter.matches_value(value))
+                } else {
+                    filter.matches_value(value)
+                }
+            })
+    }
+}
+
+/// A name=value filter from the Uri-Query of a discovery request.
+#[derive(Debug, PartialEq)]
+pub struct QueryFilter {
+    name: String,
+    value: String,
+}
+
+impl QueryFilter {
+    /// Matches the value exactly, or as a prefix when the filter value ends in a `*` wildcard.
+    fn matches_value(&self, value: &str) -> bool {
+        match self.value.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == self.value,
+        }
+    }
+}
+
+/// Parses the Uri-Query options of a request into query filters.
+/// A query without a value, e.g. `?title`, matches every link that has the attribute.
+pub fn parse_query_filters(packet: &Packet) -> Result<Vec<QueryFilter>, String> {
+    let Some(queries) = packet.get_option(CoapOption::UriQuery) else {
+        return Ok(Vec::new());
+    };
+    queries.iter().map(|query| {
+        let query = std::str::from_utf8(query).map_err(|_| "Uri-Query is not valid utf-8".to_string())?;
+        let (name, value) = query.split_once('=').unwrap_or((query, "*"));
+        if name.is_empty() {
+            return Err(format!("Uri-Query {} has no filter name", query));
+        }
+        Ok(QueryFilter { name: name.to_string(), value: value.to_string() })
+    }).collect()
+}
+
+/// Returns the links that match every one of the filters.
+pub fn filter_links<'a>(links: &'a [Link], filters: &[QueryFilter]) -> Vec<&'a Link> {
+    links.iter()
+        .filter(|link| filters.iter().all(|filter| link.matches(filter)))
+        .collect()
+}
+
+/// Writes the links in link-format (RFC 6690), one link per line.
+pub fn write_links<'a>(links: impl IntoIterator<Item = &'a Link>) -> String {
+    let mut buffer = String::new();
+    let mut write = LinkFormatWrite::new(&mut buffer);
+    write.set_add_newlines(true);
+
+    for link in links {
+        let mut attributes = write.link(&link.href);
+        for (key, value) in &link.attributes {
+            attributes = if QUOTED_ATTRIBUTES.contains(key) {
+                attributes.attr_quoted(key, value)
+            } else {
+                attributes.attr(key, value)
+            };
+        }
+    }
+    buffer
+}
+
+/// An address the broker listens on, together with the URI scheme clients use to reach it.
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint {
+    scheme: &'static str,
+    addr: SocketAddr,
+}
+
+impl Endpoint {
+    pub fn new(scheme: &'static str, addr: SocketAddr) -> Self {
+        Endpoint { scheme, addr }
+    }
+
+    /// Base URI of the endpoint for a client that reaches the broker at local_ip,
+    /// or None if the endpoint doesn't listen on that address.
+    fn uri_at(&self, local_ip: IpAddr) -> Option<String> {
+        let listens_on_ip = self.addr.ip() == local_ip
+            || (self.addr.ip().is_unspecified() && self.addr.is_ipv4() == local_ip.is_ipv4());
+        listens_on_ip.then(|| format!("{}://{}", self.scheme, SocketAddr::new(local_ip, self.addr.port())))
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.addr)
+    }
+}
+
+/// Finds the local address of the interface the broker reaches the peer through.
+/// Connecting a UDP socket sends nothing, it only makes the kernel pick the route.
+/// This is a system call, so it shouldn't be made with a lock held.
+fn local_ip_towards(peer: SocketAddr) -> std::io::Result<IpAddr> {
+    let socket = UdpSocket::bind(if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Base URIs the peer can reach the broker at, for a request from the peer that arrived on the listener bound to arrival.
+///
+/// A listener bound to a specific address tells the address the request was sent to, and the endpoints listening on
+/// that address are advertised. A listener on the wildcard address doesn't tell it, so the address of the interface
+/// the broker reaches the peer through is taken instead. If no route to the peer is found, the endpoints bound to a
+/// specific address are returned.
+pub fn advertised_uris(endpoints: &[Endpoint], arrival: SocketAddr, peer: SocketAddr) -> Vec<String> {
+    let local_ip = if arrival.ip().is_unspecified() { local_ip_towards(peer) } else { Ok(arrival.ip()) };
+    let mut uris: Vec<String> = match local_ip {
+        Ok(local_ip) => endpoints.iter().filter_map(|endpoint| endpoint.uri_at(local_ip)).collect(),
+        Err(e) => {
+            println!("No route to {}: {}", peer, e);
+            endpoints.iter().filter(|endpoint| !endpoint.addr.ip().is_unspecified()).map(|endpoint| endpoint.to_string()).collect()
+        }
+    };
+    // Listeners on a specific address and on the wildcard address of the same port show up as one uri
+    let mut seen = HashSet::new();
+    uris.retain(|uri| seen.insert(uri.clone()));
+    uris
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Links like the ones the broker serves for a collection and its topic.
+    fn links() -> Vec<Link> {
+        vec![
+            Link::new("/ps").attr("rt", "core.ps.coll"),
+            Link::new("/ps/h9392").attr("rt", "core.ps.conf").attr("title", "Temperature sensor"),
+            Link::new("/ps/data/62e4f8d").attr("rt", "core.ps.data sensor").attr("ct", "0 50"),
+        ]
+    }
+
+    /// Parses the queries as the Uri-Query options of a request.
+    fn filters(queries: &[&str]) -> Result<Vec<QueryFilter>, String> {
+        let mut packet = Packet::new();
+        for query in queries {
+            packet.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        }
+        parse_query_filters(&packet)
+    }
+
+    /// The hrefs of the links matching the queries.
+    fn matching(queries: &[&str]) -> Vec<String> {
+        let links = links();
+        filter_links(&links, &filters(queries).unwrap()).iter().map(|link| link.href.clone()).collect()
+    }
+
+    #[test]
+    fn test_filters_match_exact_values() {
+        assert_eq!(matching(&[]), ["/ps", "/ps/h9392", "/ps/data/62e4f8d"]);
+        assert_eq!(matching(&["rt=core.ps.conf"]), ["/ps/h9392"]);
+        assert_eq!(matching(&["href=/ps"]), ["/ps"]);
+        assert!(matching(&["rt=core.ps"]).is_empty());
+        // Every filter has to match
+        assert!(matching(&["rt=core.ps.conf", "href=/ps"]).is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_filters_match_prefixes() {
+        assert_eq!(matching(&["rt=core.ps.*"]), ["/ps", "/ps/h9392", "/ps/data/62e4f8d"]);
+        assert_eq!(matching(&["href=/ps/data/*"]), ["/ps/data/62e4f8d"]);
+        assert_eq!(matching(&["rt=*"]), ["/ps", "/ps/h9392", "/ps/data/62e4f8d"]);
+        assert_eq!(matching(&["title=Temp*"]), ["/ps/h9392"]);
+        // A query without a value asks for links that have the attribute at all
+        assert_eq!(matching(&["title"]), ["/ps/h9392"]);
+    }
+
+    #[test]
+    fn test_filters_match_each_value_of_multi_value_attributes() {
+        assert_eq!(matching(&["rt=sensor"]), ["/ps/data/62e4f8d"]);
+        assert_eq!(matching(&["ct=50"]), ["/ps/data/62e4f8d"]);
+        assert!(matching(&["ct=5"]).is_empty());
+        // Other attributes are matched as a whole
+        assert!(matching(&["title=sensor"]).is_empty());
+    }
+
+    #[test]
+    fn test_query_without_name_is_rejected() {
+        assert!(filters(&["=core.ps.conf"]).is_err());
+        assert!(filters(&["rt=core.ps.conf", ""]).is_err());
+    }
+
+    #[test]
+    fn test_write_links() {
+        assert_eq!(
+            write_links(&links()[..2]),
+            "</ps>;rt=\"core.ps.coll\",\n\r</ps/h9392>;rt=\"core.ps.conf\";title=\"Temperature sensor\"",
+        );
+    }
+
+    #[test]
+    fn test_endpoint_uri_at_local_address() {
+        let wildcard = Endpoint::new("coap", "0.0.0.0:5683".parse().unwrap());
+        let loopback = Endpoint::new("coap", "127.0.0.1:5684".parse().unwrap());
+        let local_ip: IpAddr = "192.168.1.20".parse().unwrap();
+
+        assert_eq!(wildcard.uri_at(local_ip).as_deref(), Some("coap://192.168.1.20:5683"));
+        assert_eq!(wildcard.uri_at("::1".parse().unwrap()), None);
+        assert_eq!(loopback.uri_at(local_ip), None);
+        assert_eq!(loopback.uri_at("127.0.0.1".parse().unwrap()).as_deref(), Some("coap://127.0.0.1:5684"));
+    }
+
+    #[test]
+    fn test_advertised_uris_at_arrival_address() {
+        let endpoints = [
+            Endpoint::new("coap", "0.0.0.0:5683".parse().unwrap()),
+            Endpoint::new("coap", "[::]:5683".parse().unwrap()),
+            Endpoint::new("coap", "127.0.0.1:5683".parse().unwrap()),
+            Endpoint::new("coap", "127.0.0.1:5684".parse().unwrap()),
+        ];
+        // A request to the loopback listener is answered with the endpoints on loopback, whatever the peer's address,
+        // and the wildcard and loopback listeners of the same port are the same uri
+        assert_eq!(
+            advertised_uris(&endpoints, "127.0.0.1:5683".parse().unwrap(), "192.168.1.30:40000".parse().unwrap()),
+            ["coap://127.0.0.1:5683", "coap://127.0.0.1:5684"],
+        );
+    }
+
+    #[test]
+    fn test_advertised_uris_towards_peer() {
+        let endpoints = [
+            Endpoint::new("coap", "0.0.0.0:5683".parse().unwrap()),
+            Endpoint::new("coap", "127.0.0.1:5683".parse().unwrap()),
+            Endpoint::new("coap", "[::]:5683".parse().unwrap()),
+        ];
+        // A request to the wildcard listener doesn't tell its address, the loopback peer is reached through loopback
+        assert_eq!(
+            advertised_uris(&endpoints, "0.0.0.0:5683".parse().unwrap(), "127.0.0.1:40000".parse().unwrap()),
+            ["coap://127.0.0.1:5683"],
+        );
+    }
+}
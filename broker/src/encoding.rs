@@ -0,0 +1,247 @@
+use crate::content_format::{CORE_PUBSUB_CBOR_CONTENT_FORMAT, JSON_CONTENT_FORMAT, MERGE_PATCH_JSON_CONTENT_FORMAT};
+use serde_cbor::Value as CborValue;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+
+/// Integer map keys of the topic configuration properties in application/core-pubsub+cbor.
+const CBOR_KEYS: [(&str, i128); 8] = [
+    ("topic-name", 0),
+    ("topic-data", 1),
+    ("resource-type", 2),
+    ("media-type", 3),
+    ("topic-type", 4),
+    ("expiration-date", 5),
+    ("max-subscribers", 6),
+    ("observer-check", 7),
+];
+
+/// Topic creation property with the first publication of the topic. It isn't part of the stored configuration.
+pub const INITIALIZE_PROPERTY: &str = "initialize";
+/// Integer map key of the initialize property in application/core-pubsub+cbor.
+const INITIALIZE_CBOR_KEY: i128 = 8;
+
+/// Encodings a topic configuration can be exchanged in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    /// Encoding of a payload with the given Content-Format, None if it isn't a topic configuration encoding.
+    /// A payload without Content-Format is taken to be json.
+    pub fn from_content_format(content_format: Option<u16>) -> Option<Encoding> {
+        match content_format {
+            None | Some(JSON_CONTENT_FORMAT) | Some(MERGE_PATCH_JSON_CONTENT_FORMAT) => Some(Encoding::Json),
+            Some(CORE_PUBSUB_CBOR_CONTENT_FORMAT) => Some(Encoding::Cbor),
+            Some(_) => None,
+        }
+    }
+
+    /// Encoding asked for with an Accept option, None if the broker can't produce it.
+    pub fn from_accept(accept: u16) -> Option<Encoding> {
+        match accept {
+            JSON_CONTENT_FORMAT => Some(Encoding::Json),
+            CORE_PUBSUB_CBOR_CONTENT_FORMAT => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn content_format(self) -> u16 {
+        match self {
+            Encoding::Json => JSON_CONTENT_FORMAT,
+            Encoding::Cbor => CORE_PUBSUB_CBOR_CONTENT_FORMAT,
+        }
+    }
+
+    /// Decodes a topic configuration, CBOR maps with integer keys are turned into the property names.
+    pub fn decode(self, payload: &[u8]) -> Result<Value, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let value: CborValue = serde_cbor::from_slice(payload).map_err(|e| e.to_string())?;
+                cbor_to_json(value)
+            }
+        }
+    }
+
+    /// Decodes a topic creation payload into the topic configuration and the value of its initialize property.
+    /// In json a string is taken as its text and any other value as its json, in CBOR a byte or text string
+    /// is taken as it is and any other value as its CBOR encoding.
+    pub fn decode_creation(self, payload: &[u8]) -> Result<(Value, Option<Vec<u8>>), String> {
+        match self {
+            Encoding::Json => {
+                let mut configuration: Value = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+                let initial_value = configuration.as_object_mut()
+                    .and_then(|properties| properties.remove(INITIALIZE_PROPERTY))
+                    .filter(|value| !value.is_null())
+                    .map(|value| match value {
+                        Value::String(text) => text.into_bytes(),
+                        value => value.to_string().into_bytes(),
+                    });
+                Ok((configuration, initial_value))
+            }
+            Encoding::Cbor => {
+                let mut configuration: CborValue = serde_cbor::from_slice(payload).map_err(|e| e.to_string())?;
+                let initial_value = match &mut configuration {
+                    CborValue::Map(entries) => entries.remove(&CborValue::Integer(INITIALIZE_CBOR_KEY))
+                        .or_else(|| entries.remove(&CborValue::Text(INITIALIZE_PROPERTY.to_string()))),
+                    _ => None,
+                };
+                let initial_value = initial_value
+                    .filter(|value| *value != CborValue::Null)
+                    .map(|value| match value {
+                        CborValue::Bytes(bytes) => bytes,
+                        CborValue::Text(text) => text.into_bytes(),
+                        value => serde_cbor::to_vec(&value).unwrap(),
+                    });
+                Ok((cbor_to_json(configuration)?, initial_value))
+            }
+        }
+    }
+
+    /// Encodes a topic configuration, in CBOR the properties get their integer keys.
+    pub fn encode(self, configuration: &Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => configuration.to_string().into_bytes(),
+            Encoding::Cbor => serde_cbor::to_vec(&json_to_cbor(configuration)).unwrap(),
+        }
+    }
+}
+
+/// Converts a decoded CBOR value to json. Map keys are integer property keys or text.
+fn cbor_to_json(value: CborValue) -> Result<Value, String> {
+    Ok(match value {
+        CborValue::Null => Value::Null,
+        CborValue::Bool(value) => Value::Bool(value),
+        CborValue::Integer(value) => {
+            let number = u64::try_from(value).map(Number::from)
+                .or_else(|_| i64::try_from(value).map(Number::from))
+                .map_err(|_| format!("integer {} is out of range", value))?;
+            Value::Number(number)
+        }
+        CborValue::Float(value) => Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null),
+        CborValue::Text(value) => Value::String(value),
+        CborValue::Array(values) => Value::Array(values.into_iter().map(cbor_to_json).collect::<Result<_, _>>()?),
+        CborValue::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let name = match key {
+                    CborValue::Integer(key) => CBOR_KEYS.iter()
+                        .find(|(_, cbor_key)| *cbor_key == key)
+                        .map(|(name, _)| name.to_string())
+                        .ok_or_else(|| format!("unknown property key {}", key))?,
+                    CborValue::Text(name) => name,
+                    _ => return Err("map keys must be integers or text".to_string()),
+                };
+                map.insert(name, cbor_to_json(value)?);
+            }
+            Value::Object(map)
+        }
+        // Tagged values such as a tag 0 date-time are taken as their content
+        CborValue::Tag(_, value) => cbor_to_json(*value)?,
+        _ => return Err("byte strings are not supported".to_string()),
+    })
+}
+
+/// Converts json to a CBOR value, known property names become their integer keys.
+fn json_to_cbor(value: &Value) -> CborValue {
+    match value {
+        Value::Null => CborValue::Null,
+        Value::Bool(value) => CborValue::Bool(*value),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => CborValue::Integer(value.into()),
+            (None, Some(value)) => CborValue::Integer(value.into()),
+            _ => CborValue::Float(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(value) => CborValue::Text(value.clone()),
+        Value::Array(values) => CborValue::Array(values.iter().map(json_to_cbor).collect()),
+        Value::Object(map) => {
+            let entries: BTreeMap<CborValue, CborValue> = map.iter().map(|(name, value)| {
+                let key = CBOR_KEYS.iter()
+                    .find(|(property, _)| property == name)
+                    .map(|(_, key)| CborValue::Integer(*key))
+                    .unwrap_or_else(|| CborValue::Text(name.clone()));
+                (key, json_to_cbor(value))
+            }).collect();
+            CborValue::Map(entries)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cbor_round_trip() {
+        let configuration = json!({
+            "topic-name": "door", "topic-data": "ps/data/door", "resource-type": "core.ps.conf", "media-type": "",
+            "topic-type": "sensor", "expiration-date": "2024-05-01T12:00:00Z", "max-subscribers": 4294967295u32, "observer-check": 86400,
+        });
+        let encoded = Encoding::Cbor.encode(&configuration);
+        assert_eq!(Encoding::Cbor.decode(&encoded).unwrap(), configuration);
+
+        // On the wire the properties have their integer keys
+        let CborValue::Map(entries) = serde_cbor::from_slice(&encoded).unwrap() else {
+            panic!("configuration isn't a CBOR map");
+        };
+        let keys: Vec<_> = entries.keys().cloned().collect();
+        assert_eq!(keys, (0..8).map(CborValue::Integer).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_cbor_decoding() {
+        // {0: "door", "topic-type": "sensor", 5: 0("2024-05-01T12:00:00Z")}
+        let mut payload = vec![0xa3, 0x00, 0x64];
+        payload.extend_from_slice(b"door");
+        payload.extend_from_slice(&[0x6a]);
+        payload.extend_from_slice(b"topic-type");
+        payload.extend_from_slice(&[0x66]);
+        payload.extend_from_slice(b"sensor");
+        payload.extend_from_slice(&[0x05, 0xc0, 0x74]);
+        payload.extend_from_slice(b"2024-05-01T12:00:00Z");
+        assert_eq!(
+            Encoding::Cbor.decode(&payload).unwrap(),
+            json!({"topic-name": "door", "topic-type": "sensor", "expiration-date": "2024-05-01T12:00:00Z"}),
+        );
+
+        // {9: 1}, {0: h'01'} and an unfinished map
+        assert!(Encoding::Cbor.decode(&[0xa1, 0x09, 0x01]).is_err());
+        assert!(Encoding::Cbor.decode(&[0xa1, 0x00, 0x41, 0x01]).is_err());
+        assert!(Encoding::Cbor.decode(&[0xa2, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_encoding_negotiation() {
+        assert_eq!(Encoding::from_content_format(None), Some(Encoding::Json));
+        assert_eq!(Encoding::from_content_format(Some(MERGE_PATCH_JSON_CONTENT_FORMAT)), Some(Encoding::Json));
+        assert_eq!(Encoding::from_content_format(Some(CORE_PUBSUB_CBOR_CONTENT_FORMAT)), Some(Encoding::Cbor));
+        // Plain application/cbor has no integer keys defined
+        assert_eq!(Encoding::from_content_format(Some(60)), None);
+
+        assert_eq!(Encoding::from_accept(JSON_CONTENT_FORMAT), Some(Encoding::Json));
+        assert_eq!(Encoding::from_accept(MERGE_PATCH_JSON_CONTENT_FORMAT), None);
+        assert_eq!(Encoding::from_accept(CORE_PUBSUB_CBOR_CONTENT_FORMAT).map(Encoding::content_format), Some(CORE_PUBSUB_CBOR_CONTENT_FORMAT));
+    }
+
+    #[test]
+    fn test_decode_creation_takes_out_initialize() {
+        let (configuration, initial_value) = Encoding::Json.decode_creation(br#"{"topic-name": "door", "initialize": "21.5"}"#).unwrap();
+        assert_eq!(configuration, json!({"topic-name": "door"}));
+        assert_eq!(initial_value.as_deref(), Some(&b"21.5"[..]));
+        // Values other than strings are kept as json
+        let (_, initial_value) = Encoding::Json.decode_creation(br#"{"topic-name": "door", "initialize": {"open": true}}"#).unwrap();
+        assert_eq!(initial_value.as_deref(), Some(&br#"{"open":true}"#[..]));
+        let (_, initial_value) = Encoding::Json.decode_creation(br#"{"topic-name": "door", "initialize": null}"#).unwrap();
+        assert_eq!(initial_value, None);
+
+        // {0: "door", 8: h'01'} and {0: "door", 8: 7}
+        let (configuration, initial_value) = Encoding::Cbor.decode_creation(&[0xa2, 0x00, 0x64, b'd', b'o', b'o', b'r', 0x08, 0x41, 0x01]).unwrap();
+        assert_eq!(configuration, json!({"topic-name": "door"}));
+        assert_eq!(initial_value.as_deref(), Some(&[0x01][..]));
+        let (_, initial_value) = Encoding::Cbor.decode_creation(&[0xa2, 0x00, 0x64, b'd', b'o', b'o', b'r', 0x08, 0x07]).unwrap();
+        assert_eq!(initial_value.as_deref(), Some(&[0x07][..]));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::confirmable::{self, Delivery};
+use crate::resource::{ObservationKey, Subscriber};
+use coap_lite::{MessageType, Packet};
+use lazy_static::lazy_static;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Confirmable notifications waiting for an answer at the same time at most, across all topics.
+pub const MAX_CONFIRMABLE_IN_FLIGHT: usize = 256;
+/// Confirmable notifications queued for a free in-flight slot at most, across all topics.
+/// An observation has at most one notification queued, so this is how many observations can wait at a time.
+/// Notifications beyond it are dropped, their subscribers get the next one.
+pub const MAX_CONFIRMABLE_QUEUED: usize = 65536;
+/// Fan-outs sending at the same time at most, across all topics. There is no queue behind them:
+/// a fan-out that doesn't get a slot is refused, see try_reserve().
+pub const MAX_CONCURRENT_FAN_OUTS: usize = 16;
+
+/// Called with a subscriber whose confirmable notification was reset or never acknowledged.
+pub type Undelivered = Arc<dyn Fn(&Subscriber, Delivery) + Send + Sync>;
+
+/// A confirmable notification for a subscriber, with what to do if it isn't delivered.
+type Queued = (Subscriber, Packet, Undelivered);
+
+lazy_static! {
+    /// Slots of the fan-outs sending at the same time.
+    static ref FAN_OUT_SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_CONCURRENT_FAN_OUTS));
+    /// Permits for the confirmable notifications in flight.
+    static ref IN_FLIGHT_PERMITS: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_CONFIRMABLE_IN_FLIGHT));
+    /// Observations with a confirmable notification in flight or queued, with the newest notification waiting behind it.
+    static ref IN_FLIGHT: Mutex<HashMap<ObservationKey, Option<(Packet, Undelivered)>>> = Mutex::new(HashMap::new());
+    /// Confirmable notifications queued for a free in-flight permit, taken in order as deliveries finish.
+    /// Permits are only taken and given back with this lock held, so nothing is queued while a permit is free.
+    static ref QUEUED: Mutex<VecDeque<Queued>> = Mutex::new(VecDeque::new());
+}
+
+/// Which notifications of a fan-out are sent as confirmable messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Confirmation {
+    /// Those of the subscribers that asked for confirmable notifications.
+    Requested,
+    /// All of them, as in the observer check.
+    Always,
+}
+
+/// A reserved fan-out slot, held until the fan-out sent on it is done.
+pub struct FanOut {
+    _slot: OwnedSemaphorePermit,
+}
+
+/// Reserves a fan-out slot, None if MAX_CONCURRENT_FAN_OUTS fan-outs are already sending.
+/// The slot is taken before the notifications are built, so a caller that doesn't get one can refuse
+/// the publication or try again later instead of queueing more work.
+pub fn try_reserve() -> Option<FanOut> {
+    FAN_OUT_SLOTS.clone().try_acquire_owned().ok().map(|slot| FanOut { _slot: slot })
+}
+
+/// Waits for a fan-out slot. Only for notifications that can't be refused or tried again, such as
+/// the final ones of a removed topic, which come at most once per topic.
+pub async fn reserve() -> FanOut {
+    FanOut { _slot: FAN_OUT_SLOTS.clone().acquire_owned().await.unwrap() }
+}
+
+impl FanOut {
+    /// Sends notifications to subscribers from the broker sockets their registrations arrived on, in a task of its own.
+    ///
+    /// Non-confirmable notifications are sent one after another on the shared sockets. A send waits for room in the
+    /// socket's send buffer but not for the subscriber.
+    ///
+    /// Confirmable notifications are retransmitted until they are answered (RFC 7252 section 4.2), with at most
+    /// MAX_CONFIRMABLE_IN_FLIGHT of them waiting for an answer at a time and at most MAX_CONFIRMABLE_QUEUED more
+    /// queued for one. The fan-out itself never waits for them, so a slow subscriber holds up neither the other
+    /// subscribers nor other topics. Each observation has at most one notification in flight and one waiting behind
+    /// it: a newer notification replaces the waiting one, which is skipped (RFC 7641 section 4.5.2).
+    pub fn send<F>(self, notifications: Vec<(Subscriber, Packet)>, confirmation: Confirmation, undelivered: F)
+    where
+        F: Fn(&Subscriber, Delivery) + Send + Sync + 'static,
+    {
+        let undelivered: Undelivered = Arc::new(undelivered);
+        tokio::spawn(async move {
+            for (subscriber, packet) in notifications {
+                if confirmation == Confirmation::Always || subscriber.is_confirmable() {
+                    queue_confirmable(subscriber, packet, undelivered.clone());
+                } else {
+                    send_non_confirmable(&subscriber, packet).await;
+                }
+            }
+            drop(self);
+        });
+    }
+}
+
+/// Sends a notification as a non-confirmable message with a new message id.
+async fn send_non_confirmable(subscriber: &Subscriber, mut packet: Packet) {
+    packet.header.set_type(MessageType::NonConfirmable);
+    packet.header.message_id = rand::random();
+    let payload = match packet.to_bytes() {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Failed to encode notification for subscriber {}: {:?}", subscriber.get_addr(), e);
+            return;
+        }
+    };
+    if let Err(e) = subscriber.get_socket().send_to(&payload, subscriber.get_addr()).await {
+        eprintln!("Failed to notify subscriber {}: {}", subscriber.get_addr(), e);
+    }
+}
+
+/// Starts delivering a confirmable notification if a permit is free, otherwise queues it.
+/// If the observation already has one in flight, the notification waits behind it instead.
+fn queue_confirmable(subscriber: Subscriber, packet: Packet, undelivered: Undelivered) {
+    let key = subscriber.get_key();
+    match IN_FLIGHT.lock().unwrap().entry(key.clone()) {
+        Entry::Occupied(mut waiting) => {
+            waiting.insert(Some((packet, undelivered)));
+            return;
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(None);
+        }
+    }
+
+    let mut queued = QUEUED.lock().unwrap();
+    if let Ok(permit) = IN_FLIGHT_PERMITS.clone().try_acquire_owned() {
+        tokio::spawn(deliver((subscriber, packet, undelivered), permit));
+    } else if queued.len() < MAX_CONFIRMABLE_QUEUED {
+        queued.push_back((subscriber, packet, undelivered));
+    } else {
+        drop(queued);
+        eprintln!("Too many confirmable notifications queued, dropping the one for subscriber {}", subscriber.get_addr());
+        IN_FLIGHT.lock().unwrap().remove(&key);
+    }
+}
+
+/// Sends a confirmable notification, then the newest one that waited behind it, until none is waiting.
+/// A notification that isn't acknowledged ends the observation, so whatever waited behind it is dropped.
+/// The permit then passes on to the next queued notification, if any.
+async fn deliver(mut next: Queued, permit: OwnedSemaphorePermit) {
+    loop {
+        let (subscriber, mut packet, mut undelivered) = next;
+        let key = subscriber.get_key();
+        loop {
+            let acknowledged = match confirmable::send_confirmable(subscriber.get_socket(), subscriber.get_addr(), packet).await {
+                Ok(Delivery::Acknowledged) => true,
+                Ok(delivery) => {
+                    undelivered(&subscriber, delivery);
+                    false
+                }
+                Err(e) => {
+                    eprintln!("Failed to notify subscriber {}: {}", subscriber.get_addr(), e);
+                    false
+                }
+            };
+
+            let waiting = {
+                let mut in_flight = IN_FLIGHT.lock().unwrap();
+                let waiting = in_flight.get_mut(&key).and_then(Option::take).filter(|_| acknowledged);
+                if waiting.is_none() {
+                    in_flight.remove(&key);
+                }
+                waiting
+            };
+            match waiting {
+                Some((waiting_packet, waiting_undelivered)) => {
+                    packet = waiting_packet;
+                    undelivered = waiting_undelivered;
+                }
+                None => break,
+            }
+        }
+
+        let mut queued = QUEUED.lock().unwrap();
+        match queued.pop_front() {
+            Some(queued_notification) => next = queued_notification,
+            None => {
+                drop(permit);
+                return;
+            }
+        }
+    }
+}
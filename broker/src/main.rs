@@ -1,76 +1,156 @@
-use coap::server::{Listener, UdpCoapListener};
-use coap_lite::link_format::LinkFormatWrite;
-use coap_lite::CoapResponse;
+use coap::server::Listener;
+use coap_lite::link_format::{LINK_ATTR_CONTENT_FORMAT, LINK_ATTR_RESOURCE_TYPE, LINK_ATTR_TITLE};
+use coap_lite::option_value::{OptionValueU16, OptionValueU32};
+use coap_lite::{CoapOption, CoapResponse, Packet};
 use coap_lite::{CoapRequest, ResponseType, RequestType as Method};
 use coap::Server;
 use socket2::{Domain, Socket, Type};
 use tokio::runtime::Runtime;
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+mod conditions;
+mod confirmable;
+mod content_format;
+mod discovery;
+mod encoding;
+mod fanout;
 mod resource;
+mod transport;
+use conditions::Conditions;
+use confirmable::Delivery;
+use fanout::Confirmation;
+use discovery::{Endpoint, Link};
+use encoding::Encoding;
+use resource::ConfigurationError;
+use resource::ObservationKey;
+use resource::Subscriber;
 use resource::Topic;
 use resource::TopicCollection;
 use serde_json::json;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use transport::SharedUdpListener;
+use std::time::Duration;
+use chrono::Utc;
 use lazy_static::lazy_static;
 
-// Topic Collection resource to store all topic-related data
-// Lock the mutex to access the topic_collection
-// let locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-// Accessing the TopicCollection from the mutex guard
-// let topic_collection_ref: &TopicCollection = &*locked_topic_collection;
+// Topic Collection resources to store all topic-related data, keyed by the collection name aka path
+// Lock the mutex to access the topic collections
+// let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+// Accessing a TopicCollection from the mutex guard
+// let topic_collection_ref: Option<&TopicCollection> = locked_topic_collections.get("ps");
 // Or if mutable collection is needed:
-// let mut locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-// let mut topic_collection_ref = Arc::get_mut(&mut locked_topic_collection)
+// let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+// let topic_collection_ref: Option<&mut TopicCollection> = locked_topic_collections.get_mut("ps");
 
 lazy_static! {
-    static ref TOPIC_COLLECTION_MUTEX: Mutex<Arc<TopicCollection>> = Mutex::new(Arc::new(TopicCollection::new("ps".to_string())));
+    // Addresses and schemes the broker listens on, filled in once the listeners are bound
+    static ref BROKER_ENDPOINTS: Mutex<Vec<Endpoint>> = Mutex::new(Vec::new());
+    static ref TOPIC_COLLECTIONS_MUTEX: Mutex<HashMap<String, TopicCollection>> = Mutex::new(HashMap::from([
+        (DEFAULT_COLLECTION.to_string(), TopicCollection::new(DEFAULT_COLLECTION.to_string())),
+    ]));
 }
+/// Name of the topic collection the broker starts with.
+const DEFAULT_COLLECTION: &str = "ps";
+
+/// Path segments that can't be used as collection names, since other broker resources live there.
+const RESERVED_COLLECTION_NAMES: [&str; 2] = [".well-known", "discovery"];
+
+/// Interval in seconds between checks for expired topics.
+const EXPIRATION_CHECK_INTERVAL: u64 = 1;
+
+/// Interval in seconds at which observer check tasks look at their topic.
+const OBSERVER_CHECK_TICK: u64 = 1;
+
+/// Interval in milliseconds at which the timed conditions of conditional observations are checked.
+const CONDITIONS_TICK_MILLIS: u64 = 250;
+
 /// SubscriptionAction enum to differentiate between subscribe and unsubscribe actions.
 enum SubscriptionAction {
     Subscribe,
     Unsubscribe,
 }
 
+/// ConfigurationAction enum to differentiate between replacing (PUT) and merge patching (PATCH/iPATCH) a topic configuration.
+enum ConfigurationAction {
+    Replace,
+    MergePatch,
+}
+
 /// Notifies client of status of request
 fn notify_client(response_type: coap_lite::ResponseType, message: &mut coap_lite::CoapResponse, payload: &str){
     message.message.payload = payload.as_bytes().to_vec();
     message.set_status(response_type);
 }
 
-/// Handles broker discovery of core.ps, returns ip address of broker
-fn handle_broker_discovery(req: &mut CoapRequest<SocketAddr>){
-    println!("Handling broker discovery");
+/// Reads the Content-Format option of a message as a number, also for formats coap-lite has no name for.
+fn get_content_format(packet: &Packet) -> Option<u16> {
+    packet.get_first_option_as::<OptionValueU16>(CoapOption::ContentFormat)
+        .and_then(|content_format| content_format.ok())
+        .map(|content_format| content_format.0)
+}
 
-    println!("Received request with payload: {}", String::from_utf8(req.message.payload.clone()).unwrap());
-    // Set correct responsetypes and content formats in the response
-    let response = req.response.as_mut().unwrap();
-    response.set_status(ResponseType::Content);
-    response.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
+/// Sets the Content-Format option of a message. Nothing is set when the format isn't known.
+fn set_content_format(packet: &mut Packet, content_format: Option<u16>) {
+    if let Some(content_format) = content_format {
+        packet.clear_option(CoapOption::ContentFormat);
+        packet.add_option_as(CoapOption::ContentFormat, OptionValueU16(content_format));
+    }
+}
+
+/// Reads the Max-Age option of a message in seconds.
+fn get_max_age(packet: &Packet) -> Option<u32> {
+    packet.get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+        .and_then(|max_age| max_age.ok())
+        .map(|max_age| max_age.0)
+}
 
-    // Create the linkformatted response containing the brokers address with rt=core.ps
-    let mut buffer = String::new();
-    let mut write = LinkFormatWrite::new(&mut buffer);
-    write.link("coap://127.0.0.1:5683")
-    .attr(coap_lite::link_format::LINK_ATTR_RESOURCE_TYPE, "core.ps");
+/// Sets the Max-Age option of a message. Without one, clients take the response to be fresh for 60 seconds.
+fn set_max_age(packet: &mut Packet, max_age: Option<u32>) {
+    if let Some(max_age) = max_age {
+        packet.clear_option(CoapOption::MaxAge);
+        packet.add_option_as(CoapOption::MaxAge, OptionValueU32(max_age));
+    }
+}
+
+/// Reads whether an Observe registration asks for confirmable notifications with the Uri-Query `con`.
+/// `con` and `con=1` ask for confirmable notifications, `con=0` or no `con` at all for non-confirmable ones.
+fn wants_confirmable_notifications(packet: &Packet) -> Result<bool, String> {
+    let Some(queries) = packet.get_option(CoapOption::UriQuery) else {
+        return Ok(false);
+    };
+    let mut confirmable = false;
+    for query in queries {
+        let query = std::str::from_utf8(query).map_err(|_| "Uri-Query is not valid utf-8".to_string())?;
+        confirmable = match query {
+            "con" | "con=1" => true,
+            "con=0" => false,
+            _ if query.starts_with("con=") => return Err(format!("Uri-Query {} must be con=0 or con=1", query)),
+            _ => continue,
+        };
+    }
+    Ok(confirmable)
+}
 
-    println!("Sending response: {}", buffer);
-  
-    // Return linkformatted response in bytes
-    response.message.payload = buffer.as_bytes().to_vec();
+/// Links to the broker itself with rt=core.ps, returned by broker discovery.
+/// There is a link for every endpoint the client can reach at the address its request arrived on.
+/// The endpoints are copied so that no lock is held during a route lookup.
+fn broker_links(arrival: SocketAddr, client_addr: SocketAddr) -> Vec<Link> {
+    let endpoints = BROKER_ENDPOINTS.lock().unwrap().clone();
+    discovery::advertised_uris(&endpoints, arrival, client_addr).into_iter()
+        .map(|uri| Link::new(uri).attr(LINK_ATTR_RESOURCE_TYPE, "core.ps"))
+        .collect()
 }
 
 /// Topic name discovery - not an actual coap pubsub draft method but very usable for testing purposes
 fn handle_discovery(req: &mut CoapRequest<SocketAddr>) {
     println!("Handling topic name/uri/datauri discovery");
 
-    // Lock the mutex to access the topic_collection
-    let locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-    // Accessing the TopicCollection from the mutex guard
-    let topic_collection_ref: &TopicCollection = &*locked_topic_collection;
+    // Lock the mutex to access the topic collections
+    let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
 
-    // Collecting all topic names, topic uri's and data uri's to [name, (topic-uri, data-uri)] vector
-    let topics = topic_collection_ref.get_topics();
-    let topics_data: Vec<(String, (String, String))> = topics.values().map(|topic| {
+    // Collecting all topic names, topic uri's and data uri's of every collection to [name, (topic-uri, data-uri)] vector
+    let topics = locked_topic_collections.values().flat_map(|topic_collection| topic_collection.get_topics().values());
+    let topics_data: Vec<(String, (String, String))> = topics.map(|topic| {
         let topic_uri = topic.get_topic_uri().to_owned();
         let data_uri = topic.get_topic_data().to_owned();
         let topic_name = topic.get_topic_name().to_owned();
@@ -91,15 +171,25 @@ fn handle_discovery(req: &mut CoapRequest<SocketAddr>) {
 /// Returns a response with the appropriate status code and payload
 /// 
 /// - On success, the payload contains status code 2.05 (Content) and the data.
-/// - On failure, the payload contains status code 4.04 (Not Found). 
-fn handle_subscription(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &str, subscriber_addr: SocketAddr, action: SubscriptionAction) {
+/// - If the topic has reached its max-subscribers, the response is 5.03 (Service Unavailable) without an Observe option.
+/// - On failure, or if the latest data has gone stale, the payload contains status code 4.04 (Not Found).
+/// - If the registration has an invalid `con` Uri-Query or conditional attribute, the response is 4.00 (Bad Request).
+///
+/// A registration with the Uri-Query `con` or `con=1` gets its notifications as confirmable messages.
+/// The conditional attributes pmin, pmax, gt, lt, st, epmin and epmax in the Uri-Query decide when it is notified.
+/// The data is sent with a Max-Age of the time it has left to be fresh, if it was published with one.
+/// A subscriber is stored with the token of its registration and the socket the registration arrived on.
+/// Observations are told apart by client endpoint and token: registering again with the same token refreshes
+/// the observation, and deregistration removes only the observation with the token of the request.
+fn handle_subscription(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_data_uri: &str, subscriber_addr: SocketAddr, socket: &Arc<tokio::net::UdpSocket>, action: SubscriptionAction) {
     println!("Beginning subscription handling");
 
-    let mut locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-    let topic_collection_ref = Arc::get_mut(&mut locked_topic_collection).unwrap(); // Lock the topic map for safe access
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap(); // Lock the topic map for safe access
+    let topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri));
 
     // Check if the topic exists
-    if let Some(topic) = topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri) {
+    if let Some(topic) = topic {
         if topic.half_created {
             // Topic does not exist, prepare an error response and respond that the subscibe action failed
             if let Some(ref mut message) = req.response {
@@ -110,27 +200,68 @@ fn handle_subscription(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &str,
             return;
         }
         }
+        let max_subscribers = topic.get_max_subscribers();
+        let content_format = topic.get_data_content_format();
         let data = topic.get_data_resource();
+        let max_age = data.remaining_max_age(Utc::now());
+        let observation: ObservationKey = (subscriber_addr, req.message.get_token().to_vec());
 
         match action {
             SubscriptionAction::Subscribe => {
-                // Topic exists, add subscriber
-                data.add_subscriber(subscriber_addr.clone());
-                println!("Current subscribers for {}: {:?}",topic_data_uri.to_string(), data.get_subscribers());
-                println!("{} subscribed to data-uri {}", subscriber_addr.clone().to_string(), topic_data_uri);
+                // Stale data isn't served, the subscription fails as if the topic had no data
+                if data.is_stale(Utc::now()) {
+                    println!("{} tried to subscribe to {} but its data is stale", subscriber_addr, topic_data_uri);
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::NotFound, message, "Topic data is stale");
+                    }
+                    return;
+                }
+
+                // Topic is full, refuse the registration with an error response, which has no Observe option
+                // and so tells the client that it was not added as a subscriber (RFC 7641 section 4.1)
+                let already_subscribed = data.is_subscriber(&observation);
+                if !already_subscribed && data.get_subscriber_count() >= max_subscribers as usize {
+                    println!("{} tried to subscribe to {} but it already has the maximum of {} subscribers", subscriber_addr, topic_data_uri, max_subscribers);
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::ServiceUnavailable, message, "Topic has reached its max-subscribers");
+                    }
+                    return;
+                }
+
+                let registration = wants_confirmable_notifications(&req.message)
+                    .and_then(|confirmable| Ok((confirmable, conditions::parse_conditional_attributes(&req.message)?)));
+                let (confirmable, attributes) = match registration {
+                    Ok(registration) => registration,
+                    Err(e) => {
+                        if let Some(ref mut message) = req.response {
+                            notify_client(coap_lite::ResponseType::BadRequest, message, &format!("Invalid query: {}", e));
+                        }
+                        return;
+                    }
+                };
+
+                // Topic exists, add subscriber or refresh its observation
+                let (addr, token) = observation;
+                let conditions = Conditions::new(attributes, data.get_data(), Utc::now());
+                if data.add_subscriber(Subscriber::new(addr, token, socket.clone(), confirmable, conditions)) {
+                    println!("{} subscribed to data-uri {}", subscriber_addr, topic_data_uri);
+                } else {
+                    println!("{} refreshed its subscription to data-uri {}", subscriber_addr, topic_data_uri);
+                }
+                println!("Current subscribers for {}: {}", topic_data_uri, data.get_subscriber_count());
 
                 // Prepare a success response
                 if let Some(ref mut message) = req.response {
-                    message.message.payload = data.get_data().clone().into_bytes().to_vec();
-                    message.message.set_content_format(coap_lite::ContentFormat::try_from(110).unwrap());
-                    message.message.set_observe_value(10001);
+                    message.message.payload = data.get_data().clone();
+                    set_content_format(&mut message.message, content_format);
+                    set_max_age(&mut message.message, max_age);
+                    message.message.set_observe_value(data.next_observe_sequence());
                 }
             }
             SubscriptionAction::Unsubscribe => {
                 // Topic exists, attempt to remove subscriber
-                if data.get_subscribers().contains(&subscriber_addr) {
-                    // Subscriber found, remove it
-                    data.remove_subscriber(subscriber_addr.clone());
+                if data.remove_subscriber(&observation) {
+                    // Subscriber found and removed
                     println!("{} unsubscribed from {}", subscriber_addr.clone(), topic_data_uri);
 
                     // Prepare a success response
@@ -161,15 +292,49 @@ fn handle_subscription(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &str,
     }
 }
 
-/// A supporting function to handle invalid paths.
-fn handle_invalid_path(req: &CoapRequest<SocketAddr>) {
+/// A supporting function to handle invalid paths, ie. paths that don't match any resource for the request method.
+///
+/// - Returns 4.05 (Method Not Allowed) if the path has the shape of a broker resource, which doesn't support the method.
+/// - Returns 4.04 (Not Found) if the path can't be a broker resource at all.
+fn handle_invalid_path(req: &mut CoapRequest<SocketAddr>) {
     // Handle unrecognized paths
     let path = req.get_path();
     println!("Invalid path requested: {}", path);
 
     let src = req.source.unwrap();
     println!("Requested by: {}", src);
+
+    // The broker root, discovery resources, collections, topic configurations and topic data
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    let is_resource_path = matches!(components.as_slice(), [] | [_] | [_, _] | [_, "data", _]);
+    let method = *req.get_method();
+
     // Set an appropriate response indicating the error
+    if let Some(ref mut message) = req.response {
+        if is_resource_path {
+            notify_client(coap_lite::ResponseType::MethodNotAllowed, message, &format!("Method {:?} is not allowed on /{}", method, path));
+        } else {
+            notify_client(coap_lite::ResponseType::NotFound, message, &format!("No resource at /{}", path));
+        }
+    }
+}
+
+/// Answers requests with a method the broker doesn't support at all with 4.05 (Method Not Allowed).
+/// Empty messages such as acknowledgements have no response and are ignored.
+fn handle_unsupported_method(req: &mut CoapRequest<SocketAddr>) {
+    let method = *req.get_method();
+    if let Some(ref mut message) = req.response {
+        println!("Error, request by method that is not supported: {:?}", method);
+        notify_client(coap_lite::ResponseType::MethodNotAllowed, message, &format!("Method {:?} is not supported by the broker", method));
+    }
+}
+
+/// Answers GET requests on topic data with an Observe value other than register (0) or deregister (1) with 4.00 (Bad Request).
+fn handle_invalid_observe(req: &mut CoapRequest<SocketAddr>) {
+    println!("Invalid Observe option on {}", req.get_path());
+    if let Some(ref mut message) = req.response {
+        notify_client(coap_lite::ResponseType::BadRequest, message, "Observe must be 0 (register) or 1 (deregister)");
+    }
 }
 
 /// Handles GET requests done to the broker, including:
@@ -180,8 +345,11 @@ fn handle_invalid_path(req: &CoapRequest<SocketAddr>) {
 /// - Subscription to a topic
 /// - Unsubscription from a topic
 /// - Retrieval of the latest data for a topic
+/// - Retrieval of a topic configuration
+/// - Listing the topic configurations of a collection
+/// - Resource discovery through /.well-known/core
 /// - Handling invalid or unvalid paths with handle_invalid_path
-fn handle_get(req: &mut CoapRequest<SocketAddr>) {
+fn handle_get(req: &mut CoapRequest<SocketAddr>, socket: &Arc<tokio::net::UdpSocket>) {
     let path = req.get_path(); // Extract the URI path from the request
 
     // Split the path into components for easier pattern matching
@@ -191,49 +359,54 @@ fn handle_get(req: &mut CoapRequest<SocketAddr>) {
         ["discovery"] => {
             handle_discovery(req);
         },
-        [".well-known", "core?rt=core.ps"] => {
-            handle_broker_discovery(req);
+        [".well-known", "core"] => {
+            match socket.local_addr() {
+                Ok(arrival) => handle_well_known_core(req, arrival),
+                Err(e) => {
+                    eprintln!("Failed to read the address of the listener: {}", e);
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::InternalServerError, message, "Broker address unavailable");
+                    }
+                }
+            }
+        },
+        [collection] => {
+            handle_get_collection(req, collection);
         },
-        ["ps", "data", topic_data_uri] => {
+        [collection, "data", topic_data_uri] => {
             if let Some(result) = req.message.get_observe_value() {
                 match result {
                     Ok(value) => {
                         // Handle value  0 aka subscribe
                         if value == 0 {
-                            handle_subscription(req, topic_data_uri, req.source.unwrap(),SubscriptionAction::Subscribe);
+                            handle_subscription(req, collection, topic_data_uri, req.source.unwrap(), socket, SubscriptionAction::Subscribe);
                             return
                         // Handle value 1 aka unsubscribe
                         } else if value == 1 {
-                            handle_subscription(req, topic_data_uri, req.source.unwrap(),SubscriptionAction::Unsubscribe);
+                            handle_subscription(req, collection, topic_data_uri, req.source.unwrap(), socket, SubscriptionAction::Unsubscribe);
                             return
                         } else {
                         // Request is erroneous
-                            handle_invalid_path(req);
+                            handle_invalid_observe(req);
                             return
                         }
                     
                     }
                     Err(_err) => {
                         // Handle error when parsing the value
-                        handle_invalid_path(req);
+                        handle_invalid_observe(req);
                         return
                     }
                 }
             // no observe value -> a single read on topics latest data
             } else {
-                handle_get_latest_data(req, topic_data_uri);
+                handle_get_latest_data(req, collection, topic_data_uri);
                 return
             }
         },
-        [".well-known", "core?rt=core.ps.conf"] => {
-            handle_topic_configuration_discovery(req);
-        },
-        [".well-known", "core?rt=core.ps.data"] => {
-            handle_topic_data_discovery(req);
+        [collection, topic_uri] => {
+            handle_get_topic_configuration(req, collection, topic_uri);
         },
-        [".well-known", "core?rt=core.ps.coll"] => {
-            handle_topic_collection_discovery(req);
-        }
         _ => {
             // Handle invalid or unrecognized paths
             handle_invalid_path(req);
@@ -241,125 +414,328 @@ fn handle_get(req: &mut CoapRequest<SocketAddr>) {
     }
 }
 
-/// Handles brokers topic collection discovery, responds with link-format containing currently hardcoded 1 topic collection
-fn handle_topic_collection_discovery(req: &mut CoapRequest<SocketAddr>) {
-    println!("Handling topic collection discovery");
+/// Link to a topic configuration resource, as found under its collection.
+fn topic_configuration_link(collection: &str, topic: &Topic) -> Link {
+    Link::new(format!("/{}/{}", collection, topic.get_topic_uri()))
+        .attr(LINK_ATTR_RESOURCE_TYPE, topic.get_resource_type())
+        .attr(LINK_ATTR_CONTENT_FORMAT, format!("{} {}", content_format::JSON_CONTENT_FORMAT, content_format::CORE_PUBSUB_CBOR_CONTENT_FORMAT))
+        .attr(LINK_ATTR_TITLE, topic.get_topic_name())
+}
+
+/// Collects a link to every pubsub resource hosted by the broker: the broker itself as seen by the client,
+/// each topic collection, and the topic configuration and topic data resources in them.
+fn hosted_links(arrival: SocketAddr, client_addr: SocketAddr) -> Vec<Link> {
+    let mut links = broker_links(arrival, client_addr);
+
+    let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    let mut topic_collections: Vec<&TopicCollection> = locked_topic_collections.values().collect();
+    topic_collections.sort_by_key(|topic_collection| topic_collection.get_name());
+    for topic_collection in topic_collections {
+        let collection = topic_collection.get_name();
+        links.push(Link::new(format!("/{}", collection))
+            .attr(LINK_ATTR_RESOURCE_TYPE, "core.ps.coll")
+            .attr(LINK_ATTR_CONTENT_FORMAT, "40"));
+        for topic in topic_collection.get_topics().values() {
+            links.push(topic_configuration_link(collection, topic));
+            let mut data_link = Link::new(format!("/{}/data/{}", collection, topic.get_topic_data()))
+                .attr(LINK_ATTR_RESOURCE_TYPE, topic.get_dr().get_resource_type());
+            if let Some(content_format) = topic.get_data_content_format() {
+                data_link = data_link.attr(LINK_ATTR_CONTENT_FORMAT, content_format.to_string());
+            }
+            links.push(data_link.attr(LINK_ATTR_TITLE, topic.get_topic_name()));
+        }
+    }
+    links
+}
 
-    let locked_topic_collection = match TOPIC_COLLECTION_MUTEX.lock() {
-        Ok(lock) => lock,
+/// Handles GET requests on /.well-known/core (RFC 6690), responds with link-format containing every pubsub resource hosted by the broker.
+///
+/// The Uri-Query options filter the links by href or by any attribute, e.g. rt, if, ct or title.
+/// A filter value ending in `*` matches by prefix, and a link has to match every filter given.
+/// The broker itself is advertised at the address of the listener the request arrived on, arrival.
+///
+/// - Returns 2.05 (Content) with the matching links, possibly none.
+/// - Returns 4.00 (Bad Request) if a Uri-Query isn't a valid filter.
+fn handle_well_known_core(req: &mut CoapRequest<SocketAddr>, arrival: SocketAddr) {
+    println!("Handling resource discovery");
+
+    let filters = discovery::parse_query_filters(&req.message);
+    let Some(ref mut response) = req.response else {
+        return;
+    };
+    let filters = match filters {
+        Ok(filters) => filters,
         Err(e) => {
-            println!("Failed to lock TOPIC_COLLECTION_MUTEX: {}", e);
+            notify_client(coap_lite::ResponseType::BadRequest, response, &format!("Invalid query: {}", e));
             return;
         }
     };
 
-    let topic_collection = &*locked_topic_collection;
-    let mut buffer = String::new();
-    let mut write = LinkFormatWrite::new(&mut buffer);
-    write.set_add_newlines(true);
+    let links = hosted_links(arrival, req.source.unwrap());
+    let matching_links = discovery::filter_links(&links, &filters);
+    println!("{} of {} links matched the query", matching_links.len(), links.len());
 
-    write.link(&format!("/{}", topic_collection.get_name()))
-    .attr(coap_lite::link_format::LINK_ATTR_RESOURCE_TYPE, "core.ps.coll")
-    .attr(coap_lite::link_format::LINK_ATTR_CONTENT_FORMAT, "40");
-
-    if let Some(ref mut response) = req.response {
-        response.message.payload = buffer.as_bytes().to_vec();
-        response.set_status(coap_lite::ResponseType::Content);
-        response.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
-        println!("Topic collection discovery response sent succesfully")
-    } else {
-        println!("Failed to set response payload");
-    }
+    response.message.payload = discovery::write_links(matching_links).into_bytes();
+    response.set_status(coap_lite::ResponseType::Content);
+    response.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
 }
 
-/// Handles get request with rt="core.ps.data" and responds with link-format with topic data resource uris
-fn handle_topic_data_discovery(req: &mut CoapRequest<SocketAddr>) {
-    println!("Handling topic data discovery");
+/// Handles GET requests on a topic collection, responds with link-format containing the topic configurations of the collection.
+///
+/// - Returns 2.05 (Content) with the links of the topics in the collection.
+/// - Returns 4.04 (Not Found) if no collection with the name exists.
+fn handle_get_collection(req: &mut CoapRequest<SocketAddr>, collection: &str) {
+    println!("Handling get request on topic collection: {}", collection);
 
-    let locked_topic_collection = match TOPIC_COLLECTION_MUTEX.lock() {
-        Ok(lock) => lock,
-        Err(e) => {
-            println!("Failed to lock TOPIC_COLLECTION_MUTEX: {}", e);
-            return;
-        }
+    let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+
+    let Some(ref mut message) = req.response else {
+        return;
     };
+    let Some(topic_collection) = locked_topic_collections.get(collection) else {
+        notify_client(coap_lite::ResponseType::NotFound, message, "Topic collection not found");
+        return;
+    };
+
+    let links: Vec<Link> = topic_collection.get_topics().values()
+        .map(|topic| topic_configuration_link(collection, topic))
+        .collect();
+
+    message.message.payload = discovery::write_links(&links).into_bytes();
+    message.set_status(coap_lite::ResponseType::Content);
+    message.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
+}
 
-    let topic_collection = &*locked_topic_collection;
+/// Handles GET requests on a topic configuration resource ({collection}/{topic-uri}).
+///
+/// - Returns 2.05 (Content) with the full topic configuration if the topic exists, as json or CBOR depending on Accept.
+/// - Returns 4.04 (Not Found) if no topic with the uri exists.
+/// - Returns 4.06 (Not Acceptable) if Accept asks for another format.
+fn handle_get_topic_configuration(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_uri: &str) {
+    println!("Handling get request on topic configuration: {}", topic_uri);
+    let Some((_, response_encoding)) = configuration_encodings(req) else {
+        return;
+    };
 
-    let mut buffer = String::new();
-    let mut write = LinkFormatWrite::new(&mut buffer);
-    write.set_add_newlines(true);
+    let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    let topic = locked_topic_collections.get(collection)
+        .and_then(|topic_collection| topic_collection.find_topic_by_uri(topic_uri));
 
-    for topic in topic_collection.get_topics().values() {
-        let data_resource = topic.get_dr();
-        if data_resource.get_resource_type() == "core.ps.data" {
-            write.link(&format!("/ps/data/{}", topic.get_topic_data()))
-                 .attr(coap_lite::link_format::LINK_ATTR_RESOURCE_TYPE, "core.ps.data");
+    if let Some(ref mut message) = req.response {
+        if let Some(topic) = topic {
+            respond_with_configuration(message, coap_lite::ResponseType::Content, &topic.get_configuration(collection), response_encoding);
+        } else {
+            message.set_status(coap_lite::ResponseType::NotFound);
+            message.message.payload = b"Topic not found".to_vec();
         }
     }
+}
 
-    if let Some(ref mut response) = req.response {
-        response.message.payload = buffer.as_bytes().to_vec();
-        response.set_status(coap_lite::ResponseType::Content);
-        response.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
-    } else {
-        println!("Failed to set response payload");
+/// Handling put requests done to the broker, including:
+/// - Publishing data to a topic (update_topic_data())
+/// - Replacing a topic configuration (update_topic_configuration())
+async fn handle_put(req: &mut CoapRequest<SocketAddr>) {
+    let path_str = req.get_path();
+    let components: Vec<&str> = path_str.split('/').filter(|s| !s.is_empty()).collect();
+
+    match components.as_slice() {
+        [collection, "data", topic_data_uri] => {
+            update_topic_data(req, collection, topic_data_uri).await;
+        },
+        [collection, topic_uri] => {
+            update_topic_configuration(req, collection, topic_uri, ConfigurationAction::Replace);
+        },
+        _ => {
+            eprintln!("Unsupported path: {}", path_str);
+            handle_invalid_path(req);
+        },
     }
 }
 
-/// Handles topic-configuration discovery of core.ps.conf, returns link format with topic uris
-fn handle_topic_configuration_discovery(req: &mut CoapRequest<SocketAddr>) {
-    println!("Handling topic configuration discovery");
-    
-    // Lock the mutex to access the topic_collection
-    let locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-    // Accessing the TopicCollection from the mutex guard
-    let topic_collection: &TopicCollection = &*locked_topic_collection;
+/// Sets the response matching a rejected topic configuration.
+fn notify_configuration_error(message: &mut CoapResponse, error: &ConfigurationError) {
+    let response_type = match error {
+        ConfigurationError::Invalid(_) => coap_lite::ResponseType::BadRequest,
+        ConfigurationError::Immutable(_) => coap_lite::ResponseType::Forbidden,
+        ConfigurationError::Conflict(_) => coap_lite::ResponseType::Conflict,
+    };
+    notify_client(response_type, message, &error.to_string());
+}
 
-    let mut buffer = String::new();
-    let mut write = LinkFormatWrite::new(&mut buffer);
-    write.set_add_newlines(true);
+/// Works out the encoding of the topic configuration in the request payload from its Content-Format,
+/// and the encoding of the response from the Accept option, which defaults to the encoding of the request.
+///
+/// Answers with 4.15 (Unsupported Content-Format) or 4.06 (Not Acceptable) and returns None if an encoding isn't supported.
+fn configuration_encodings(req: &mut CoapRequest<SocketAddr>) -> Option<(Encoding, Encoding)> {
+    let content_format = get_content_format(&req.message);
+    let accept = req.message.get_first_option_as::<OptionValueU16>(CoapOption::Accept)
+        .and_then(|accept| accept.ok())
+        .map(|accept| accept.0);
+    let message = req.response.as_mut()?;
+
+    let Some(request_encoding) = Encoding::from_content_format(content_format) else {
+        notify_client(coap_lite::ResponseType::UnsupportedContentFormat, message, "Topic configurations are application/json or application/core-pubsub+cbor");
+        return None;
+    };
+    let response_encoding = match accept {
+        Some(accept) => match Encoding::from_accept(accept) {
+            Some(response_encoding) => response_encoding,
+            None => {
+                notify_client(coap_lite::ResponseType::NotAcceptable, message, "Topic configurations are available as application/json or application/core-pubsub+cbor");
+                return None;
+            }
+        },
+        None => request_encoding,
+    };
+    Some((request_encoding, response_encoding))
+}
 
-    for topic in topic_collection.get_topics().values() {
-        if topic.get_resource_type() == "core.ps.conf" {
-            write.link(&format!("/ps/{}", topic.get_topic_uri()))
-                 .attr(coap_lite::link_format::LINK_ATTR_RESOURCE_TYPE, "core.ps.conf")
-                 .attr(coap_lite::link_format::LINK_ATTR_CONTENT_FORMAT, "TBD");
+/// Decodes the topic configuration in the request payload, answers with 4.00 (Bad Request) and returns None if it isn't valid.
+fn decode_configuration(req: &mut CoapRequest<SocketAddr>, encoding: Encoding) -> Option<serde_json::Value> {
+    match encoding.decode(&req.message.payload) {
+        Ok(configuration) => Some(configuration),
+        Err(e) => {
+            if let Some(ref mut message) = req.response {
+                notify_client(coap_lite::ResponseType::BadRequest, message, &format!("Invalid topic configuration: {}", e));
+            }
+            None
         }
     }
+}
 
-    // Set correct responsetypes and content formats in the response
-    if let Some(ref mut response) = req.response {
-        response.message.payload = buffer.as_bytes().to_vec();
-        response.set_status(coap_lite::ResponseType::Content);
-        response.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
-    } else {
-        println!("Failed to set response payload");
+/// Sets a topic configuration as the response payload, in the given encoding.
+fn respond_with_configuration(message: &mut CoapResponse, response_type: ResponseType, configuration: &serde_json::Value, encoding: Encoding) {
+    message.set_status(response_type);
+    message.message.payload = encoding.encode(configuration);
+    set_content_format(&mut message.message, Some(encoding.content_format()));
+}
+
+/// Handles requests with method FETCH, including:
+/// - Filtered discovery of topic configurations in the collection (handle_topic_fetch())
+/// - Invalid or unrecognized paths (handle_invalid_path())
+fn handle_fetch(req: &mut CoapRequest<SocketAddr>) {
+    let path = req.get_path();
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+    match components.as_slice() {
+        [collection] => {
+            handle_topic_fetch(req, collection);
+        },
+        _ => {
+            handle_invalid_path(req);
+        },
     }
 }
 
-/// Handling put requests done to the broker
-async fn handle_put(req: &mut CoapRequest<SocketAddr>) {
-    let path_str = req.get_path();
-    let components: Vec<&str> = path_str.split('/').filter(|s| !s.is_empty()).collect();
+/// Handles FETCH on the topic collection. The payload is a partial topic configuration in json or CBOR
+/// and the response is link-format with the topic configurations matching all of its properties.
+///
+/// - Returns 2.05 (Content) with the links of the matching topics, possibly none.
+/// - Returns 4.00 (Bad Request) if the payload is not a valid partial topic configuration.
+/// - Returns 4.04 (Not Found) if no collection with the name exists.
+/// - Returns 4.15 (Unsupported Content-Format) if the payload is neither json nor CBOR.
+fn handle_topic_fetch(req: &mut CoapRequest<SocketAddr>, collection: &str) {
+    println!("Handling topic configuration fetch");
+
+    let Some(request_encoding) = Encoding::from_content_format(get_content_format(&req.message)) else {
+        if let Some(ref mut message) = req.response {
+            notify_client(coap_lite::ResponseType::UnsupportedContentFormat, message, "Topic configurations are application/json or application/core-pubsub+cbor");
+        }
+        return;
+    };
+    let Some(filter) = decode_configuration(req, request_encoding) else {
+        return;
+    };
+
+    let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
 
-    // Now expecting at least 3 components: collection, data, topic-data-uri
-    if components.len() < 3 {
-        eprintln!("Invalid path format. Received: {}", path_str);
+    let Some(ref mut message) = req.response else {
+        return;
+    };
+    let Some(topic_collection) = locked_topic_collections.get(collection) else {
+        notify_client(coap_lite::ResponseType::NotFound, message, "Topic collection not found");
         return;
+    };
+    let topics = match topic_collection.filter_topics(&filter) {
+        Ok(topics) => topics,
+        Err(e) => {
+            println!("Rejected topic configuration filter: {}", e);
+            notify_configuration_error(message, &e);
+            return;
+        }
+    };
+
+    let links: Vec<Link> = topics.iter()
+        .map(|topic| topic_configuration_link(collection, topic))
+        .collect();
+    println!("{} topics matched the filter", topics.len());
+
+    message.message.payload = discovery::write_links(&links).into_bytes();
+    message.set_status(coap_lite::ResponseType::Content);
+    message.message.set_content_format(coap_lite::ContentFormat::ApplicationLinkFormat);
+}
+
+/// Handles requests with method PATCH or iPATCH, including:
+/// - Partial update of a topic configuration (update_topic_configuration())
+/// - Invalid or unrecognized paths (handle_invalid_path())
+fn handle_patch(req: &mut CoapRequest<SocketAddr>) {
+    let path = req.get_path();
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+    match components.as_slice() {
+        [collection, topic_uri] => {
+            update_topic_configuration(req, collection, topic_uri, ConfigurationAction::MergePatch);
+        },
+        _ => {
+            handle_invalid_path(req);
+        },
     }
+}
+
+/// Updates a topic configuration resource ({collection}/{topic-uri}), either replacing the whole configuration (PUT)
+/// or applying a merge patch to it (PATCH/iPATCH). The payload is json, merge-patch+json or core-pubsub+cbor.
+///
+/// - Returns 2.04 (Changed) with the stored configuration if the update was applied.
+/// - Returns 4.00 (Bad Request) if the payload is not a valid topic configuration or patch.
+/// - Returns 4.03 (Forbidden) if the update tries to change the topic-data.
+/// - Returns 4.04 (Not Found) if no topic with the uri exists.
+/// - Returns 4.06 (Not Acceptable) or 4.15 (Unsupported Content-Format) for other formats.
+fn update_topic_configuration(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_uri: &str, action: ConfigurationAction) {
+    println!("Updating configuration of topic: {}", topic_uri);
+
+    let Some((request_encoding, response_encoding)) = configuration_encodings(req) else {
+        return;
+    };
+    let Some(configuration) = decode_configuration(req, request_encoding) else {
+        return;
+    };
 
-    let collection = components[0];
-    let uri = components[1];
-    let topic_data_uri = components[2];
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    let topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_uri_mut(topic_uri));
 
-    // Ensure the request uri is what we expect, e.g., ps/data/DATA-URI
-    if uri != "data" || collection != "ps" {
-        eprintln!("Unsupported path: {}", path_str);
+    let Some(ref mut message) = req.response else {
         return;
-    }
+    };
+    let Some(topic) = topic else {
+        notify_client(coap_lite::ResponseType::NotFound, message, "Topic not found");
+        return;
+    };
 
-    update_topic_data(req, topic_data_uri).await;
+    let result = match action {
+        ConfigurationAction::Replace => topic.replace_configuration(&configuration, collection),
+        ConfigurationAction::MergePatch => topic.patch_configuration(&configuration, collection),
+    };
+    match result {
+        Ok(()) => {
+            println!("Configuration of topic {} updated", topic_uri);
+            respond_with_configuration(message, coap_lite::ResponseType::Changed, &topic.get_configuration(collection), response_encoding);
+        }
+        Err(e) => {
+            println!("Rejected configuration for topic {}: {}", topic_uri, e);
+            notify_configuration_error(message, &e);
+        }
+    }
 }
 
 /// Updates data resource associated with a topic
@@ -367,65 +743,94 @@ async fn handle_put(req: &mut CoapRequest<SocketAddr>) {
 /// - Returns 2.01 (Created) if the topic was created successfully.
 /// - Returns 2.04 (Changed) if the topic was updated successfully.
 /// - Returns 4.04 (Not Found) if the topic was not found.
-async fn update_topic_data(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &str) {
+///
+/// The publication is stored with its publish time and the Max-Age option of the request, if it has one.
+async fn update_topic_data(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_data_uri: &str) {
     println!("Updating topic-data uri: {}",topic_data_uri);
-    let payload = match String::from_utf8(req.message.payload.clone()) {
-        Ok(content) => content,
-        Err(_) => {
-            eprintln!("Failed to decode payload as UTF-8");
-            return;
+    let payload = req.message.payload.clone();
+    let publication_content_format = get_content_format(&req.message);
+    let max_age = get_max_age(&req.message);
+
+    // The notifications need a fan-out slot, a publication that can't have one isn't stored
+    let Some(fan_out) = fanout::try_reserve() else {
+        println!("Refused publication to {}: too many notifications being sent", topic_data_uri);
+        if let Some(ref mut message) = req.response {
+            notify_client(coap_lite::ResponseType::ServiceUnavailable, message, "Too many notifications are being sent, try again later");
         }
+        return;
     };
 
     // Lock the mutex
-    let mut locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
     let mut created = false;
     let mut updated = false;
     let topic: &mut Topic;
-    // Obtain a mutable reference to the TopicCollection inside the Arc
-    if let Some(topic_collection_ref) = Arc::get_mut(&mut locked_topic_collection) {
+    // Obtain a mutable reference to the TopicCollection hosting the data
+    if let Some(topic_collection_ref) = locked_topic_collections.get_mut(collection) {
         // Attempt to find the topic by its topic_data
         if let Some(ttopic) = topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri) {
             topic = ttopic;
+            // Data has to be in the topic's media type, a publication without Content-Format is taken to be in it
+            if let (Some(expected), Some(received)) = (topic.get_content_format(), publication_content_format) {
+                if expected != received {
+                    println!("Rejected publication to {}: Content-Format {} doesn't match the topic's {}", topic_data_uri, received, expected);
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::UnsupportedContentFormat, message, &format!("Topic data must have Content-Format {}", expected));
+                    }
+                    return;
+                }
+            }
+            let content_format = publication_content_format.or(topic.get_content_format());
             // Action is "data", update the topic's resource
             //If the topic has default data resource, make a new one and set it to the topic, and return 2.01 Created
             if topic.half_created == true {
-                topic.get_data_resource().set_data(payload.to_string());
+                topic.get_data_resource().publish(payload.clone(), content_format, max_age, Utc::now());
                 topic.half_created = false;
                 created = true;
                 
             }
             // Otherwise, update the existing data resource and return 2.04 Updated
             else {
-                topic.get_data_resource().set_data(payload.to_string());
+                topic.get_data_resource().publish(payload.clone(), content_format, max_age, Utc::now());
                 updated = true;
             }
         }
         else{
             println!("SETTING TOPIC DATA FAILED");
             if let Some(ref mut message)=req.response{
-                notify_client(coap_lite::ResponseType::NotFound,message,"");
+                notify_client(coap_lite::ResponseType::NotFound,message,"Topic not found");
             }
             return;
         }
     }
     else{
         println!("Couldnt open topic collection");
+        if let Some(ref mut message) = req.response {
+            notify_client(coap_lite::ResponseType::NotFound, message, "Topic collection not found");
+        }
         return;
     }
 
-    // Notify all subscribers of the update
-    for subscriber in topic.get_dr().get_subscribers() {
-        // Clone the necessary data and move it into the async block
-        let subscriber_clone = subscriber.clone();
-        let resource = topic.get_dr().get_data().to_owned();
-
-        println!("Informing {}",subscriber_clone);
-        tokio::spawn(async move {
-            if let Err(e) = inform_subscriber(subscriber_clone, coap_lite::ResponseType::Changed, &resource).await {
-                eprintln!("Failed to notify subscriber {}: {}", subscriber_clone, e);
+    // Notify the subscribers whose conditions the update meets, the notifications share the next Observe sequence number
+    let now = Utc::now();
+    let content_format = topic.get_data_content_format();
+    let topic_uri = topic.get_topic_uri().to_string();
+    let data_resource = topic.get_data_resource();
+    let observe_sequence = data_resource.next_observe_sequence();
+    let notifications: Vec<_> = data_resource.get_subscribers_mut()
+        .filter_map(|subscriber| {
+            let conditions = subscriber.get_conditions_mut();
+            if !conditions.on_publish(&payload, now) {
+                return None;
             }
-        });
+            conditions.notified(&payload, now);
+            Some((subscriber.clone(), notification(subscriber, coap_lite::ResponseType::Changed, &payload, content_format, max_age, observe_sequence)))
+        })
+        .collect();
+    if !notifications.is_empty() {
+        println!("Informing {} subscribers of {}", notifications.len(), topic_data_uri);
+        let collection = collection.to_string();
+        fan_out.send(notifications, Confirmation::Requested, move |subscriber, delivery| remove_undelivered(&collection, &topic_uri, subscriber, delivery));
     }
 
     if let Some(ref mut message) = req.response {
@@ -442,73 +847,341 @@ async fn update_topic_data(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &s
     }
 }
 
-/// Informs a subscriber of a change in the topic data.
-async fn inform_subscriber(addr: SocketAddr, response_type: ResponseType, resource: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let packet = coap_lite::Packet::new();
+/// Builds a notification for a subscriber, echoing the token of the subscriber's registration.
+/// The data is sent with the given Content-Format and Max-Age, and observe_sequence is the Observe value of the notification.
+fn notification(subscriber: &Subscriber, response_type: ResponseType, resource: &[u8], content_format: Option<u16>, max_age: Option<u32>, observe_sequence: u32) -> Packet {
+    let mut packet = coap_lite::Packet::new();
+    packet.header.code = coap_lite::MessageClass::Response(response_type);
+    packet.set_token(subscriber.get_token().to_vec());
+    packet.payload = resource.to_vec();
+    // Error responses end the subscription, so they are sent without content format and Observe option
+    if u8::from(coap_lite::MessageClass::Response(response_type)) < 0x80 {
+        set_content_format(&mut packet, content_format);
+        set_max_age(&mut packet, max_age);
+        packet.set_observe_value(observe_sequence);
+    }
+    packet
+}
+
+/// Ends the observation of a subscriber of a topic whose confirmable notification was reset or never acknowledged.
+fn remove_undelivered(collection: &str, topic_uri: &str, subscriber: &Subscriber, delivery: Delivery) {
+    println!("Subscriber {} of topic {} didn't acknowledge a notification ({:?}), removing it", subscriber.get_addr(), topic_uri, delivery);
+    remove_observation(collection, topic_uri, subscriber);
+}
 
-    let mut message = CoapResponse::new(&packet).unwrap();
-    message.set_status(response_type);
-    message.message.payload = resource.as_bytes().to_vec();
-    message.message.set_content_format(coap_lite::ContentFormat::try_from(110).unwrap());
-    message.message.set_observe_value(10002);
+/// Removes the observation of a subscriber from a topic, if the topic still exists.
+fn remove_observation(collection: &str, topic_uri: &str, subscriber: &Subscriber) {
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    let topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_uri_mut(topic_uri));
+    if let Some(topic) = topic {
+        topic.get_data_resource().remove_subscriber(&subscriber.get_key());
+    }
+}
 
-    let payload = message.message.to_bytes().unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.send_to(&payload, &addr)?;
+/// Sends a final 4.04 (Not Found) notification to every subscriber of a topic that was removed from the broker,
+/// or whose topic data was deleted.
+fn notify_topic_removed(topic: &Topic, reason: &str) {
+    let notifications: Vec<_> = topic.get_dr().get_subscribers()
+        .map(|subscriber| (subscriber.clone(), notification(subscriber, coap_lite::ResponseType::NotFound, reason.as_bytes(), None, None, 0)))
+        .collect();
+    if notifications.is_empty() {
+        return;
+    }
 
-    Ok(())
+    println!("Informing {} subscribers that topic-data {} was removed", notifications.len(), topic.get_topic_data());
+    // These notifications can't be sent later, so they wait for a fan-out slot. The observations end with them either way.
+    tokio::spawn(async move {
+        fanout::reserve().await.send(notifications, Confirmation::Requested, |_, _| {});
+    });
 }
 
-/// Creates a new topic based on name and resource type as arguments.
+/// Periodically removes the topics whose expiration-date has passed and informs their subscribers.
+async fn expire_topics() {
+    let mut interval = tokio::time::interval(Duration::from_secs(EXPIRATION_CHECK_INTERVAL));
+    loop {
+        interval.tick().await;
+
+        let expired_topics: Vec<Topic> = {
+            let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+            locked_topic_collections.values_mut()
+                .flat_map(|topic_collection_ref| topic_collection_ref.remove_expired_topics(Utc::now()))
+                .collect()
+        };
+
+        for topic in expired_topics {
+            println!("Topic {} expired and was removed", topic.get_topic_uri());
+            notify_topic_removed(&topic, "Topic expired");
+        }
+    }
+}
+
+/// Periodically sends the notifications conditional observations are due: data that met the conditions
+/// but had to wait for pmin or epmin to pass, and the latest data again once pmax has passed.
+/// These notifications are 2.05 (Content) with what is left of the data's freshness as Max-Age.
+/// Stale data isn't sent, the observations wait for the next publication.
+async fn notify_conditional_subscribers() {
+    let mut interval = tokio::time::interval(Duration::from_millis(CONDITIONS_TICK_MILLIS));
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now();
+        let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+        for (collection, topic_collection) in locked_topic_collections.iter_mut() {
+            for topic in topic_collection.get_topics_mut().values_mut() {
+                if topic.half_created || topic.get_dr().is_stale(now) {
+                    continue;
+                }
+                // Without a fan-out slot the conditions are left as they are and checked again on the next tick
+                let Some(fan_out) = fanout::try_reserve() else {
+                    continue;
+                };
+                let content_format = topic.get_data_content_format();
+                let topic_uri = topic.get_topic_uri().to_string();
+                let data_resource = topic.get_data_resource();
+                let data = data_resource.get_data().clone();
+                let max_age = data_resource.remaining_max_age(now);
+
+                let due: Vec<Subscriber> = data_resource.get_subscribers_mut()
+                    .filter(|subscriber| subscriber.get_conditions().is_conditional())
+                    .filter_map(|subscriber| {
+                        let conditions = subscriber.get_conditions_mut();
+                        if !conditions.on_tick(&data, now) {
+                            return None;
+                        }
+                        conditions.notified(&data, now);
+                        Some(subscriber.clone())
+                    })
+                    .collect();
+                if due.is_empty() {
+                    continue;
+                }
+
+                let observe_sequence = data_resource.next_observe_sequence();
+                let notifications: Vec<_> = due.into_iter()
+                    .map(|subscriber| {
+                        let packet = notification(&subscriber, coap_lite::ResponseType::Content, &data, content_format, max_age, observe_sequence);
+                        (subscriber, packet)
+                    })
+                    .collect();
+                println!("Informing {} subscribers of {} as their conditions ask", notifications.len(), topic.get_topic_data());
+                let collection = collection.clone();
+                fan_out.send(notifications, Confirmation::Requested, move |subscriber, delivery| remove_undelivered(&collection, &topic_uri, subscriber, delivery));
+            }
+        }
+    }
+}
+
+/// Observer check of a topic in a collection, runs for as long as the topic exists.
+///
+/// Every observer-check seconds, each subscriber receives a confirmable notification with the latest data.
+/// The interval is read from the topic on every tick, so configuration changes apply right away.
+/// The notification's Max-Age is what is left of the data's freshness. Stale data isn't sent, so the check
+/// waits until new data is published.
+async fn check_observers(collection: String, topic_uri: String) {
+    let mut interval = tokio::time::interval(Duration::from_secs(OBSERVER_CHECK_TICK));
+    let mut last_check = tokio::time::Instant::now();
+    loop {
+        interval.tick().await;
+
+        // Only the interval and freshness are read every tick, the subscribers and data once a round is due
+        let (observe_check, stale) = {
+            let locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+            let topic = locked_topic_collections.get(&collection)
+                .and_then(|topic_collection| topic_collection.find_topic_by_uri(&topic_uri));
+            let Some(topic) = topic else {
+                println!("Topic {} no longer exists, stopping its observer check", topic_uri);
+                return;
+            };
+            (topic.get_observe_check(), topic.get_dr().is_stale(Utc::now()))
+        };
+        if stale || last_check.elapsed() < Duration::from_secs(observe_check.into()) {
+            continue;
+        }
+        // Without a fan-out slot the round is tried again on the next tick
+        let Some(fan_out) = fanout::try_reserve() else {
+            continue;
+        };
+        last_check = tokio::time::Instant::now();
+
+        // The notifications of a round are notifications like any other and take the next Observe sequence number
+        let (subscribers, data, content_format, max_age, observe_sequence) = {
+            let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+            let topic = locked_topic_collections.get_mut(&collection)
+                .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_uri_mut(&topic_uri));
+            let Some(topic) = topic else {
+                println!("Topic {} no longer exists, stopping its observer check", topic_uri);
+                return;
+            };
+            let content_format = topic.get_data_content_format();
+            let data_resource = topic.get_data_resource();
+            let now = Utc::now();
+            (data_resource.get_subscribers().cloned().collect::<Vec<_>>(), data_resource.get_data().clone(), content_format,
+             data_resource.remaining_max_age(now), data_resource.next_observe_sequence())
+        };
+
+        // The probes are confirmable whether or not the subscribers asked for confirmable notifications. An observation
+        // whose previous notification is still unanswered gets its probe after it, so probes of slow subscribers don't pile up.
+        let probes: Vec<_> = subscribers.into_iter()
+            .map(|subscriber| {
+                let packet = notification(&subscriber, coap_lite::ResponseType::Content, &data, content_format, max_age, observe_sequence);
+                (subscriber, packet)
+            })
+            .collect();
+        let (collection, topic_uri) = (collection.clone(), topic_uri.clone());
+        fan_out.send(probes, Confirmation::Always, move |subscriber, delivery| {
+            println!("Subscriber {} of topic {} failed the observer check ({:?}), removing it", subscriber.get_addr(), topic_uri, delivery);
+            remove_observation(&collection, &topic_uri, subscriber);
+        });
+    }
+}
+
+/// Creates a new topic in a collection from the topic configuration sent by the client.
 /// 
-/// - Returns 2.01 (Created) if the topic was created successfully.
-fn create_topic(topic_name: &String, resource_type: &String, req: &mut coap_lite::CoapRequest<SocketAddr>) {
-    let topic = Topic::new(topic_name.clone(), resource_type.clone());
-    let topic_uri = topic.get_topic_uri();
-    let topic_data = topic.get_topic_data().to_owned();
-    let location = "ps/".to_string() + &topic_uri;
-    let data_location = "ps/data/".to_string() + &topic_data;
-    let mut locked_topic_collection: std::sync::MutexGuard<'_, Arc<TopicCollection>> = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-    let mut topic_collection_ref = Arc::get_mut(&mut locked_topic_collection);
-    topic_collection_ref.as_mut().unwrap().add_topic(topic);
-    println!("Topic '{}' with uri: {}, data-uri: {}, and of type '{}' added to the topic map.", topic_name, topic_uri, topic_data, resource_type);
+/// - Returns 2.01 (Created) with the stored configuration if the topic was created successfully.
+/// - Returns 4.00 (Bad Request) if the configuration is invalid, e.g. resource-type is not "core.ps.conf".
+/// - Returns 4.04 (Not Found) if no collection with the name exists.
+/// - Returns 4.09 (Conflict) if the requested topic-data is already in use.
+///
+/// With an initial value the topic is fully created right away, its data being the initial value in the topic's media type.
+/// The response tells in topic-state whether the topic is half-created or fully created.
+fn create_topic(configuration: &serde_json::Value, initial_value: Option<Vec<u8>>, req: &mut coap_lite::CoapRequest<SocketAddr>, collection: &str, response_encoding: Encoding) {
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    let Some(topic_collection_ref) = locked_topic_collections.get_mut(collection) else {
+        if let Some(ref mut message) = req.response {
+            notify_client(coap_lite::ResponseType::NotFound, message, "Topic collection not found");
+        }
+        return;
+    };
+    let collection_name = collection.to_string();
+
+    let topic_uri = match topic_collection_ref.create_topic(configuration) {
+        Ok(topic_uri) => topic_uri,
+        Err(e) => {
+            println!("Rejected topic creation: {}", e);
+            if let Some(ref mut message) = req.response {
+                notify_configuration_error(message, &e);
+            }
+            return;
+        }
+    };
+    let topic = topic_collection_ref.find_topic_by_uri_mut(&topic_uri).unwrap();
+    if let Some(initial_value) = initial_value {
+        let content_format = topic.get_content_format();
+        topic.get_data_resource().publish(initial_value, content_format, None, Utc::now());
+        topic.half_created = false;
+    }
+    let location = format!("{}/{}", collection_name, topic_uri);
+    let mut payload = topic.get_configuration(&collection_name);
+    payload["topic-state"] = json!(if topic.half_created { "half-created" } else { "fully-created" });
+    // The json response also carries the location in the payload, for clients that don't read the options
+    if response_encoding == Encoding::Json {
+        payload["Location-Path"] = json!(location);
+    }
+    println!("Topic '{}' with uri: {}, data-uri: {}, and of type '{}' added to the topic map.", topic.get_topic_name(), topic_uri, topic.get_topic_data(), topic.get_resource_type());
+    tokio::spawn(check_observers(collection_name, topic_uri.clone()));
 
     if let Some(ref mut message) = req.response {
-        let payload = json!({"Location-Path": location,
-    "topic-name": topic_name,
-    "topic-data": data_location,
-    "resource-type": resource_type});
-        message.message.payload = payload.to_string().into_bytes().to_vec();
-        message.set_status(coap_lite::ResponseType::Created);
+        for segment in location.split('/') {
+            message.message.add_option(coap_lite::CoapOption::LocationPath, segment.as_bytes().to_vec());
+        }
+        respond_with_configuration(message, coap_lite::ResponseType::Created, &payload, response_encoding);
     }
 }
 
+/// Creates a new topic collection from the collection-name sent by the client.
+///
+/// - Returns 2.01 (Created) with the collection path as Location-Path if the collection was created successfully.
+/// - Returns 4.00 (Bad Request) if the name is missing, isn't a single path segment or is reserved for other broker resources.
+/// - Returns 4.09 (Conflict) if a collection with the name already exists.
+fn create_collection(payload: &serde_json::Value, req: &mut CoapRequest<SocketAddr>) {
+    let Some(ref mut message) = req.response else {
+        return;
+    };
+    let Some(name) = payload.get("collection-name").and_then(|name| name.as_str()) else {
+        notify_client(coap_lite::ResponseType::BadRequest, message, "collection-name is mandatory and must be a string");
+        return;
+    };
+    if name.is_empty() || name.contains('/') || name.contains('?') || RESERVED_COLLECTION_NAMES.contains(&name) {
+        notify_client(coap_lite::ResponseType::BadRequest, message, &format!("{} is not a valid collection name", name));
+        return;
+    }
+
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
+    if locked_topic_collections.contains_key(name) {
+        notify_client(coap_lite::ResponseType::Conflict, message, &format!("Topic collection {} already exists", name));
+        return;
+    }
+    locked_topic_collections.insert(name.to_string(), TopicCollection::new(name.to_string()));
+    println!("Topic collection {} added to the broker.", name);
+
+    message.message.add_option(coap_lite::CoapOption::LocationPath, name.as_bytes().to_vec());
+    notify_client(coap_lite::ResponseType::Created, message, "Topic collection created");
+}
+
 /// Handles post requests, including:
-/// - Creation of a new topic (create_topic())
+/// - Creation of a new topic collection (create_collection())
+/// - Creation of a new topic in a collection (create_topic())
 /// - Invalid or unrecognized paths (handle_invalid_path())
 fn handle_post(req:&mut Box<CoapRequest<SocketAddr>>){
-     // Extract payload from request
-     let payload = String::from_utf8_lossy(&req.message.payload);
-
-     // Parse payload to obtain topic-name and resource-type
-     let parsed_payload: serde_json::Value = serde_json::from_str(payload.as_ref()).unwrap();
-     let topic_name: &String = &parsed_payload["topic-name"].as_str().unwrap().to_string();
-     let resource_type: &String = &parsed_payload["resource-type"].as_str().unwrap().to_string();
+    let path = req.get_path();
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
 
-    // Add the topic to the topic map
-    create_topic(topic_name, resource_type, req);
+    match components.as_slice() {
+        [] => {
+            if !matches!(get_content_format(&req.message), None | Some(content_format::JSON_CONTENT_FORMAT)) {
+                if let Some(ref mut message) = req.response {
+                    notify_client(coap_lite::ResponseType::UnsupportedContentFormat, message, "Collections are created with application/json");
+                }
+                return;
+            }
+            match serde_json::from_slice::<serde_json::Value>(&req.message.payload) {
+                Ok(payload) => create_collection(&payload, req),
+                Err(e) => {
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::BadRequest, message, &format!("Invalid collection payload: {}", e));
+                    }
+                }
+            }
+        },
+        [collection] => {
+            // Decode payload to obtain the topic configuration, in json or CBOR
+            let Some((request_encoding, response_encoding)) = configuration_encodings(req) else {
+                return;
+            };
+            match request_encoding.decode_creation(&req.message.payload) {
+                Ok((configuration, initial_value)) => create_topic(&configuration, initial_value, req, collection, response_encoding),
+                Err(e) => {
+                    if let Some(ref mut message) = req.response {
+                        notify_client(coap_lite::ResponseType::BadRequest, message, &format!("Invalid topic configuration: {}", e));
+                    }
+                }
+            }
+        },
+        _ => {
+            handle_invalid_path(req);
+        },
+    }
 }
 
 /// Handles requests with method DELETE, including:
+/// - Deletion of a topic collection (delete_collection())
 /// - Deletion of a topic (delete_topic())
+/// - Deletion of the data of a topic (delete_topic_data())
 /// - Invalid or unrecognized paths (handle_invalid_path())
 async fn handle_delete(req: &mut CoapRequest<SocketAddr>) {
     let path = req.get_path(); // Extract the URI path from the request
     let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
 
     match components.as_slice() {
-        [topic_uri] => {
-            delete_topic(req, topic_uri, req.source.unwrap());
+        [collection] => {
+            delete_collection(req, collection, req.source.unwrap());
+        },
+        [collection, topic_uri] => {
+            delete_topic(req, collection, topic_uri, req.source.unwrap());
+        },
+        [collection, "data", topic_data_uri] => {
+            delete_topic_data(req, collection, topic_data_uri, req.source.unwrap());
         },
         _ => {
             // Handle invalid or unrecognized paths
@@ -516,21 +1189,87 @@ async fn handle_delete(req: &mut CoapRequest<SocketAddr>) {
         },
     }
 }
-/// Handles deletion of a topic
+/// Handles deletion of a topic collection together with all of its topics.
+/// Every subscriber of those topics gets a final 4.04 (Not Found) notification.
+///
+/// - Returns 2.02 (Deleted) if the collection was found and deleted successfully
+/// - Returns 4.03 (Forbidden) for the default collection, which always exists
+/// - Returns 4.04 (Not Found) if no collection with the name exists
+fn delete_collection(req: &mut CoapRequest<SocketAddr>, collection: &str, local_addr: SocketAddr) {
+    println!("Deleting topic collection: {}", collection);
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap(); // Lock the topic map for safe access
+
+    let Some(ref mut message) = req.response else {
+        return;
+    };
+    if collection == DEFAULT_COLLECTION {
+        notify_client(coap_lite::ResponseType::Forbidden, message, "The default topic collection can't be deleted");
+        println!("{} tried to delete the default collection {}", local_addr, collection);
+    } else if let Some(topic_collection) = locked_topic_collections.remove(collection) {
+        for topic in topic_collection.get_topics().values() {
+            notify_topic_removed(topic, "Topic collection deleted");
+        }
+        notify_client(coap_lite::ResponseType::Deleted, message, "Topic collection deleted succesfully");
+        println!("{} deleted collection {}", local_addr, collection);
+    } else {
+        notify_client(coap_lite::ResponseType::NotFound, message, "Topic collection not found");
+        println!("{} tried to delete {} but no collection with that name exists", local_addr, collection);
+    }
+}
+
+/// Handles deletion of the data resource of a topic, the topic configuration stays and returns to half-created state.
+/// Every subscriber of the data gets a final 4.04 (Not Found) notification, which ends the subscription.
+///
+/// - Returns 2.02 (Deleted) if the topic had data and it was deleted
+/// - Returns 4.04 (Not Found) if no topic with the data uri exists, or the topic has no data yet
+fn delete_topic_data(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_data_uri: &str, local_addr: SocketAddr) {
+    println!("Deleting topic data: {}", topic_data_uri);
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap(); // Lock the topic map for safe access
+    let topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri));
+
+    let Some(ref mut message) = req.response else {
+        return;
+    };
+    match topic {
+        Some(topic) if !topic.half_created => {
+            notify_topic_removed(topic, "Topic data deleted");
+            let data_resource = topic.get_data_resource();
+            data_resource.clear_data();
+            data_resource.set_subscribers(Vec::new());
+            topic.half_created = true;
+            notify_client(coap_lite::ResponseType::Deleted, message, "Topic data deleted succesfully");
+            println!("{} deleted the data of {}", local_addr, topic_data_uri);
+        }
+        _ => {
+            notify_client(coap_lite::ResponseType::NotFound, message, "Topic data not found");
+            println!("{} tried to delete {} but no topic data with that uri exists", local_addr, topic_data_uri);
+        }
+    }
+}
+
+/// Handles deletion of a topic. Every subscriber of the topic gets a final 4.04 (Not Found) notification.
 /// 
-/// Returns 2.02 (Deleted) if the topic was found and deleted successfully
-// TO DO: all subscribers MUST be unsubscribed after this
-fn delete_topic(req: &mut CoapRequest<SocketAddr>, topic_uri: &str, local_addr: SocketAddr) {
+/// - Returns 2.02 (Deleted) if the topic was found and deleted successfully
+/// - Returns 4.04 (Not Found) if no topic with the uri exists
+fn delete_topic(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_uri: &str, local_addr: SocketAddr) {
     println!("Deleting topic: {}", topic_uri);
-    let mut locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap(); // Lock the topic map for safe access
-    let mut topic_collection_ref = Arc::get_mut(&mut locked_topic_collection);
-    
-    topic_collection_ref.as_mut().unwrap().remove_topic(topic_uri);
-        // Topic found and removed
-        if let Some(ref mut message) = req.response {
-            notify_client(coap_lite::ResponseType::Deleted, message, "Topic deleted succesfully");
-            println!("{} deleted {}", local_addr.to_string(), topic_uri);
-        }
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap(); // Lock the topic map for safe access
+    let removed_topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.remove_topic(topic_uri));
+
+    let Some(ref mut message) = req.response else {
+        return;
+    };
+    if let Some(topic) = removed_topic {
+        // Topic found and removed, its subscriptions end with it
+        notify_topic_removed(&topic, "Topic deleted");
+        notify_client(coap_lite::ResponseType::Deleted, message, "Topic deleted succesfully");
+        println!("{} deleted {}", local_addr, topic_uri);
+    } else {
+        notify_client(coap_lite::ResponseType::NotFound, message, "Topic not found");
+        println!("{} tried to delete {} but no topic with that uri exists", local_addr, topic_uri);
+    }
 }
 
 /// Handles GET requests for the latest data of a topic.
@@ -538,15 +1277,18 @@ fn delete_topic(req: &mut CoapRequest<SocketAddr>, topic_uri: &str, local_addr:
 /// - Returns 2.05 (Content) if the topic was found and the latest data was returned with data. Only possible for existing topics.
 /// that have been fully created, ie. published with data.
 /// - Returns 4.04 (Not Found) if the topic was not found, or in half-created state.
-fn handle_get_latest_data(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &str) {
+/// - Returns 4.04 (Not Found) if the data was published with a Max-Age and has gone stale.
+///
+/// The data is sent with a Max-Age of the time it has left to be fresh, if it was published with one.
+fn handle_get_latest_data(req: &mut CoapRequest<SocketAddr>, collection: &str, topic_data_uri: &str) {
     println!("Handling get request on topic's latest data");
-    // Lock the mutex to access the topic collection
-    let mut locked_topic_collection = TOPIC_COLLECTION_MUTEX.lock().unwrap();
-
-    let topic_collection_ref = Arc::get_mut(&mut locked_topic_collection).unwrap(); // Lock the topic map for safe access
+    // Lock the mutex to access the topic collections
+    let mut locked_topic_collections = TOPIC_COLLECTIONS_MUTEX.lock().unwrap();
 
     // Find the topic by its data URI
-    if let Some(topic) = topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri) {
+    let topic = locked_topic_collections.get_mut(collection)
+        .and_then(|topic_collection_ref| topic_collection_ref.find_topic_by_data_uri_mut(topic_data_uri));
+    if let Some(topic) = topic {
         // Check if the topic is fully created
         if topic.half_created {
             // Topic is not in fully created state, return 4.04 (Not Found)
@@ -554,13 +1296,22 @@ fn handle_get_latest_data(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &st
                 message.set_status(coap_lite::ResponseType::NotFound);
                 message.message.payload = b"Topic data not found".to_vec();
             }
+        } else if topic.get_dr().is_stale(Utc::now()) {
+            // Data is older than its Max-Age, it isn't served anymore
+            println!("Topic data {} is stale", topic_data_uri);
+            if let Some(ref mut message) = req.response {
+                notify_client(coap_lite::ResponseType::NotFound, message, "Topic data is stale");
+            }
         } else {
-            // Topic is fully created, return the latest data
-            let data = topic.get_data_resource().get_data().clone(); // Assuming get_data() returns the latest data
+            // Topic is fully created, return the latest data in its Content-Format
+            let data = topic.get_dr().get_data().clone();
+            let content_format = topic.get_data_content_format();
+            let max_age = topic.get_dr().remaining_max_age(Utc::now());
             if let Some(ref mut message) = req.response {
                 message.set_status(coap_lite::ResponseType::Content);
-                message.message.payload = data.into_bytes().to_vec();
-                message.message.set_content_format(coap_lite::ContentFormat::ApplicationJSON);
+                message.message.payload = data;
+                set_content_format(&mut message.message, content_format);
+                set_max_age(&mut message.message, max_age);
             }
         }
     } else {
@@ -572,52 +1323,77 @@ fn handle_get_latest_data(req: &mut CoapRequest<SocketAddr>, topic_data_uri: &st
     }
 }
 
+/// Creates a nonblocking UDP socket with SO_REUSEADDR and binds it to addr.
+/// Both listeners use port 5683, so every socket bound to it has to allow address reuse.
+fn reusable_socket(addr: SocketAddr) -> Socket {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(socket2::Protocol::UDP)).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    socket.set_reuse_address(true).unwrap();
+    socket.bind(&addr.into()).unwrap();
+    socket
+}
+
+/// Dispatches a request to the handler of its method. socket is the broker socket the request arrived on.
+async fn handle_request(mut request: Box<CoapRequest<SocketAddr>>, socket: Arc<tokio::net::UdpSocket>) -> Box<CoapRequest<SocketAddr>> {
+    match request.get_method() {
+        &Method::Get => handle_get(&mut request, &socket),
+        &Method::Post => handle_post(&mut request),
+        &Method::Put => handle_put(&mut request).await,
+        &Method::Delete => handle_delete(&mut request).await,
+        &Method::Fetch => handle_fetch(&mut request),
+        &Method::Patch | &Method::IPatch => handle_patch(&mut request),
+        _ => handle_unsupported_method(&mut request),
+    };
+    // respond to request
+    request
+}
+
+/// Runs a coap server on one of the broker sockets. The handler gets the socket along with every request,
+/// so that subscriptions know which socket to send their notifications from.
+async fn serve(socket: Arc<tokio::net::UdpSocket>) -> std::io::Result<()> {
+    let listeners: Vec<Box<dyn Listener>> = vec![Box::new(SharedUdpListener::new(socket.clone()))];
+    let mut server = Server::from_listeners(listeners);
+
+    // remove basic functionality of handling get requests with observe setting
+    server.disable_observe_handling(true).await;
+
+    server.run(move |request: Box<CoapRequest<SocketAddr>>| handle_request(request, socket.clone())).await
+}
+
 /// server startup and handling requests is implemented in main 
 fn main() {
     let addr = "127.0.0.1:5683";
     Runtime::new().unwrap().block_on(async move {
-        // create socket2 socket and assign a random address to it, then join multicast group with it
-        // and attempt to make these nonblocking and reusable
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(socket2::Protocol::UDP)).unwrap();
-        socket.set_nonblocking(true).unwrap();
-        socket.set_reuse_address(true).unwrap();
+        // create a nonblocking and reusable socket2 socket on all interfaces, then join multicast group with it
         let addr2 = "0.0.0.0:5683".parse::<std::net::SocketAddr>().unwrap();
-        socket.bind(&addr2.into()).unwrap();
+        let socket = reusable_socket(addr2);
         // multicast address for ipv4 coap is 224.0.1.187:5683
         let multiaddr = Ipv4Addr::new(224, 0, 1, 187);
         socket.join_multicast_v4(&multiaddr, &Ipv4Addr::UNSPECIFIED).unwrap();
 
         // create std socket from socket2 socket and then tokio socket from std socket
         let sock = UdpSocket::from(socket);
-        let socket_multi = tokio::net::UdpSocket::from_std(sock).unwrap();
+        let socket_multi = Arc::new(tokio::net::UdpSocket::from_std(sock).unwrap());
 
-        // and socket from 127.0.0.1:5683
-        let socket_local = tokio::net::UdpSocket::bind(addr).await.unwrap();
+        // and socket from 127.0.0.1:5683, also reusable since the port is shared with the socket above
+        let socket_local = Arc::new(tokio::net::UdpSocket::from_std(UdpSocket::from(reusable_socket(addr.parse().unwrap()))).unwrap());
 
-        // create server from listeners
-        let mut listeners: Vec<Box<dyn Listener>> = Vec::new();
-        let listener1 = Box::new(UdpCoapListener::from_socket(socket_local));
-        let listener2 =  Box::new(UdpCoapListener::from_socket(socket_multi));
-        listeners.push(listener1);
-        listeners.push(listener2);
-        let mut server = Server::from_listeners(listeners);
+        // the broker advertises the addresses it actually listens on in broker discovery
+        *BROKER_ENDPOINTS.lock().unwrap() = vec![
+            Endpoint::new("coap", socket_local.local_addr().unwrap()),
+            Endpoint::new("coap", socket_multi.local_addr().unwrap()),
+        ];
 
-        // remove basic functionality of handling get requests with observe setting
-        server.disable_observe_handling(true).await;
+        // remove topics once their expiration-date passes
+        tokio::spawn(expire_topics());
+        tokio::spawn(notify_conditional_subscribers());
         
-        println!("Broker up on {}, listening for requests.", addr);
-
-        // run the server and process requests
-        server.run(|mut request: Box<CoapRequest<SocketAddr>>| async {
-            match request.get_method() {
-                &Method::Get => handle_get(&mut *request),
-                &Method::Post => handle_post(&mut request),
-                &Method::Put => handle_put(&mut *request).await,
-                &Method::Delete => handle_delete(&mut *request).await,
-                _ => println!("Error, request by method that is not supported."),
-            };
-            // respond to request
-            return request;
-        }).await.unwrap();
+        let endpoints: Vec<String> = BROKER_ENDPOINTS.lock().unwrap().iter().map(|endpoint| endpoint.to_string()).collect();
+        println!("Broker up on {}, listening for requests.", endpoints.join(", "));
+
+        // run a server on each socket and process requests, notifications leave from the socket their registration came in on
+        let (local, multi) = tokio::join!(serve(socket_local), serve(socket_multi));
+        local.unwrap();
+        multi.unwrap();
     });
-}
\ No newline at end of file
+}
@@ -1,11 +1,16 @@
 use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
 use rand::Rng;
+use chrono::{DateTime, SecondsFormat, Utc};
+use crate::conditions::Conditions;
+use serde_json::{json, Map, Value};
 
 
 ///Generate random len 6 String consisting of numbers and/or letters as the uri. 2,2 billion possibilities
 ///This is used for generating random uris for topics and data resources.
-///Currently doesn't check for possible same uris!! TODO
+///Uniqueness within a collection is guaranteed by TopicCollection::generate_unique_uri.
 fn generate_uri() -> String {
     let mut rng = rand::thread_rng();
 
@@ -24,6 +29,166 @@ fn generate_uri() -> String {
     random_string
 }
 
+/// Default time in seconds between observer checks of a topic.
+const DEFAULT_OBSERVE_CHECK: u32 = 86400;
+
+/// The only resource type accepted for topic configurations.
+const TOPIC_RESOURCE_TYPE: &str = "core.ps.conf";
+
+/// Properties a topic configuration can contain.
+const CONFIGURATION_PROPERTIES: [&str; 8] = [
+    "topic-name",
+    "topic-data",
+    "resource-type",
+    "media-type",
+    "topic-type",
+    "expiration-date",
+    "max-subscribers",
+    "observer-check",
+];
+
+/// Media types a topic can be configured with, and their CoAP Content-Format ids (RFC 7252 section 12.3).
+const MEDIA_TYPES: [(&str, u16); 14] = [
+    ("text/plain", 0),
+    ("text/plain;charset=utf-8", 0),
+    ("application/link-format", 40),
+    ("application/xml", 41),
+    ("application/octet-stream", 42),
+    ("application/exi", 47),
+    ("application/json", 50),
+    ("application/cbor", 60),
+    ("application/senml+json", 110),
+    ("application/sensml+json", 111),
+    ("application/senml+cbor", 112),
+    ("application/sensml+cbor", 113),
+    ("application/senml+xml", 310),
+    ("application/sensml+xml", 311),
+];
+
+/// Looks up the Content-Format id of a media type. Case and whitespace around parameters are ignored.
+pub fn content_format_of_media_type(media_type: &str) -> Option<u16> {
+    let media_type: String = media_type.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+    MEDIA_TYPES.iter()
+        .find(|(name, _)| *name == media_type)
+        .map(|(_, content_format)| *content_format)
+}
+
+/// Reasons for rejecting a topic configuration sent by a client.
+#[derive(Debug)]
+pub enum ConfigurationError {
+    /// The configuration is malformed or has an invalid property value, answered with 4.00 (Bad Request).
+    Invalid(String),
+    /// The configuration tries to change a property that cannot be changed, answered with 4.03 (Forbidden).
+    Immutable(String),
+    /// The configuration requests a uri that is already in use, answered with 4.09 (Conflict).
+    Conflict(String),
+}
+
+impl std::fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigurationError::Invalid(reason) => write!(f, "Invalid topic configuration: {}", reason),
+            ConfigurationError::Immutable(reason) => write!(f, "Topic configuration cannot be changed: {}", reason),
+            ConfigurationError::Conflict(reason) => write!(f, "Topic configuration conflicts with an existing topic: {}", reason),
+        }
+    }
+}
+
+/// Checks that the configuration only contains properties known to the broker.
+fn check_known_properties(configuration: &Map<String, Value>) -> Result<(), ConfigurationError> {
+    match configuration.keys().find(|key| !CONFIGURATION_PROPERTIES.contains(&key.as_str())) {
+        Some(key) => Err(ConfigurationError::Invalid(format!("unknown property {}", key))),
+        None => Ok(()),
+    }
+}
+
+/// Reads an optional text string property from the configuration.
+fn text_property(configuration: &Map<String, Value>, name: &str) -> Result<Option<String>, ConfigurationError> {
+    match configuration.get(name) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(ConfigurationError::Invalid(format!("{} must be a text string", name))),
+    }
+}
+
+/// Reads an optional unsigned integer property from the configuration, the value has to fit in u32.
+fn uint_property(configuration: &Map<String, Value>, name: &str) -> Result<Option<u32>, ConfigurationError> {
+    match configuration.get(name) {
+        None => Ok(None),
+        Some(value) => value.as_u64()
+            .and_then(|value| u32::try_from(value).ok())
+            .map(Some)
+            .ok_or_else(|| ConfigurationError::Invalid(format!("{} must be an unsigned integer", name))),
+    }
+}
+
+/// Returns the data uri from a topic-data given either as a plain uri or as a path under the collection.
+fn data_uri_from_path<'a>(topic_data: &'a str, collection_name: &str) -> &'a str {
+    let data_prefix = format!("{}/data/", collection_name);
+    let topic_data = topic_data.trim_start_matches('/');
+    topic_data.strip_prefix(&data_prefix).unwrap_or(topic_data)
+}
+
+/// Checks a topic-data requested by a client and returns the data uri from it.
+/// The data uri has to be a single non-empty path segment.
+fn validate_data_uri(topic_data: &str, collection_name: &str) -> Result<String, ConfigurationError> {
+    let data_uri = data_uri_from_path(topic_data, collection_name);
+    if data_uri.is_empty() || data_uri.contains('/') || data_uri.contains('?') {
+        return Err(ConfigurationError::Invalid(format!("topic-data {} is not a valid data uri", topic_data)));
+    }
+    Ok(data_uri.to_string())
+}
+
+/// Checks the topic-name, which is mandatory and can't be empty.
+fn validate_topic_name(topic_name: Option<String>) -> Result<String, ConfigurationError> {
+    match topic_name {
+        Some(topic_name) if !topic_name.is_empty() => Ok(topic_name),
+        Some(_) => Err(ConfigurationError::Invalid("topic-name can't be empty".to_string())),
+        None => Err(ConfigurationError::Invalid("topic-name is mandatory".to_string())),
+    }
+}
+
+/// Checks the resource-type, which is mandatory and has to be "core.ps.conf".
+fn validate_resource_type(resource_type: Option<String>) -> Result<String, ConfigurationError> {
+    match resource_type {
+        Some(resource_type) if resource_type == TOPIC_RESOURCE_TYPE => Ok(resource_type),
+        Some(resource_type) => Err(ConfigurationError::Invalid(format!("resource-type {} is not supported, only {} is accepted", resource_type, TOPIC_RESOURCE_TYPE))),
+        None => Err(ConfigurationError::Invalid("resource-type is mandatory".to_string())),
+    }
+}
+
+/// Parses an expiration-date, which has to be an RFC 3339 date-time such as "2030-01-01T00:00:00Z".
+fn parse_expiration_date(expiration_date: &str) -> Result<DateTime<Utc>, ConfigurationError> {
+    DateTime::parse_from_rfc3339(expiration_date)
+        .map(|expiration_date| expiration_date.with_timezone(&Utc))
+        .map_err(|e| ConfigurationError::Invalid(format!("expiration-date {} is not an RFC 3339 date-time: {}", expiration_date, e)))
+}
+
+/// Parses the expiration-date of a configuration, which also has to lie after now.
+fn validate_expiration_date(expiration_date: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, ConfigurationError> {
+    let parsed = parse_expiration_date(expiration_date)?;
+    if parsed <= now {
+        return Err(ConfigurationError::Invalid(format!("expiration-date {} has already passed", expiration_date)));
+    }
+    Ok(parsed)
+}
+
+/// Checks the media-type, which has to be one the broker knows the Content-Format of. An empty media-type leaves the format open.
+fn validate_media_type(media_type: String) -> Result<String, ConfigurationError> {
+    if !media_type.is_empty() && content_format_of_media_type(&media_type).is_none() {
+        return Err(ConfigurationError::Invalid(format!("media-type {} is not supported", media_type)));
+    }
+    Ok(media_type)
+}
+
+/// Checks the observer-check interval, which has to be at least one second.
+fn validate_observe_check(observe_check: u32) -> Result<u32, ConfigurationError> {
+    if observe_check == 0 {
+        return Err(ConfigurationError::Invalid("observer-check must be at least 1 second".to_string()));
+    }
+    Ok(observe_check)
+}
+
 
 ///Topic resource as struct and its implemented methods. Represents a topic in the broker, that is, topic configuration and dataresource.
 ///
@@ -46,8 +211,8 @@ pub struct Topic {
     pub media_type: String,
     /// The type of the topic.
     pub topic_type: String,
-    /// The expiration date of the topic.
-    pub expiration_date: String,
+    /// The expiration date of the topic, after which the broker removes it. None if the topic doesn't expire.
+    pub expiration_date: Option<DateTime<Utc>>,
     /// The maximum number of subscribers allowed for the topic.
     pub max_subscribers: u32,
     /// The amount of time in seconds between each observer check, removing uninterested observers
@@ -77,9 +242,9 @@ impl Topic {
             topic_data: topic_data,
             media_type: String::new(),
             topic_type: String::new(),
-            expiration_date: String::new(),
+            expiration_date: None,
             max_subscribers: u32::MAX,
-            observe_check: 86400,
+            observe_check: DEFAULT_OBSERVE_CHECK,
             data_resource: data_resource,
             half_created: true,
         }
@@ -106,12 +271,14 @@ impl Topic {
     pub fn get_dr (&self) -> &DataResource{
         &self.data_resource
     }
-    ///Set the URI of the topic.
+    ///Set the URI of the topic, also updating the parent topic of its data resource.
     pub fn set_topic_uri(&mut self, topic_uri: String) {
+        self.data_resource.set_parent_topic_uri(topic_uri.clone());
         self.topic_uri = topic_uri;
     }
-    ///Set the data of the topic.
+    ///Set the data uri of the topic, also moving its data resource to the new uri.
     pub fn set_topic_data(&mut self, topic_data: String) {
+        self.data_resource.set_data_uri(topic_data.clone());
         self.topic_data = topic_data;
     }
     ///Set the media type of the topic data.
@@ -123,7 +290,7 @@ impl Topic {
         self.topic_type = topic_type;
     }
     ///Set the expiration date of the topic.
-    pub fn set_expiration_date(&mut self, expiration_date: String) {
+    pub fn set_expiration_date(&mut self, expiration_date: Option<DateTime<Utc>>) {
         self.expiration_date = expiration_date;
     }
     ///Set the maximum number of subscribers allowed for the topic. Max value is determined by u32
@@ -162,13 +329,27 @@ impl Topic {
     pub fn get_media_type(&self) -> &str {
         &self.media_type
     }
+    ///Get the Content-Format id of the topic's media type, None if the topic has no media type.
+    pub fn get_content_format(&self) -> Option<u16> {
+        content_format_of_media_type(&self.media_type)
+    }
+    ///Get the Content-Format id of the topic data: the one the data was published with, which a publication
+    ///without Content-Format takes from the topic's media type. A later media-type change doesn't relabel stored data.
+    ///Without data, the one of the topic's media type.
+    pub fn get_data_content_format(&self) -> Option<u16> {
+        self.data_resource.get_content_format().or(self.get_content_format())
+    }
     ///Get the type of the topic.
     pub fn get_topic_type(&self) -> &str {
         &self.topic_type
     }
     ///Get the expiration date of the topic.
-    pub fn get_expiration_date(&self) -> &str {
-        &self.expiration_date
+    pub fn get_expiration_date(&self) -> Option<DateTime<Utc>> {
+        self.expiration_date
+    }
+    ///Check whether the topic has expired at the given time.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expiration_date.is_some_and(|expiration_date| expiration_date <= now)
     }
     ///Get the maximum number of subscribers allowed for the topic.
     pub fn get_max_subscribers(&self) -> u32 {
@@ -178,6 +359,119 @@ impl Topic {
     pub fn get_observe_check(&self) -> u32 {
         self.observe_check
     }
+    ///Get the full topic configuration as json, topic-data is given as a path under the hosting collection.
+    pub fn get_configuration(&self, collection_name: &str) -> Value {
+        let mut configuration = json!({
+            "topic-name": self.topic_name,
+            "topic-data": format!("{}/data/{}", collection_name, self.topic_data),
+            "resource-type": self.resource_type,
+            "media-type": self.media_type,
+            "topic-type": self.topic_type,
+            "max-subscribers": self.max_subscribers,
+            "observer-check": self.observe_check,
+        });
+        if let Some(expiration_date) = self.expiration_date {
+            configuration["expiration-date"] = json!(expiration_date.to_rfc3339_opts(SecondsFormat::Secs, true));
+        }
+        configuration
+    }
+    /// Creates a new half-created topic from the json configuration sent at topic creation.
+    ///
+    /// topic-name and resource-type are mandatory, every other property is optional and falls back to its default.
+    /// A requested topic-data is used as the data uri, otherwise one is generated.
+    pub fn from_configuration(configuration: &Value, collection_name: &str) -> Result<Self, ConfigurationError> {
+        let properties = configuration.as_object()
+            .ok_or_else(|| ConfigurationError::Invalid("configuration must be an object".to_string()))?;
+        check_known_properties(properties)?;
+
+        let topic_name = validate_topic_name(text_property(properties, "topic-name")?)?;
+        let resource_type = validate_resource_type(text_property(properties, "resource-type")?)?;
+        let mut topic = Topic::new(topic_name, resource_type);
+        if let Some(topic_data) = text_property(properties, "topic-data")? {
+            topic.set_topic_data(validate_data_uri(&topic_data, collection_name)?);
+        }
+        topic.replace_configuration(configuration, collection_name)?;
+        Ok(topic)
+    }
+    /// Replaces the topic configuration with the given json object, as done with a PUT on the topic configuration.
+    ///
+    /// topic-name and resource-type are mandatory, the other properties return to their defaults when left out.
+    /// topic-data can only be repeated with its current value. Nothing is changed if the configuration is rejected.
+    pub fn replace_configuration(&mut self, configuration: &Value, collection_name: &str) -> Result<(), ConfigurationError> {
+        let configuration = configuration.as_object()
+            .ok_or_else(|| ConfigurationError::Invalid("configuration must be an object".to_string()))?;
+        check_known_properties(configuration)?;
+
+        let topic_name = validate_topic_name(text_property(configuration, "topic-name")?)?;
+        let resource_type = validate_resource_type(text_property(configuration, "resource-type")?)?;
+        if let Some(topic_data) = text_property(configuration, "topic-data")? {
+            self.check_topic_data(&topic_data, collection_name)?;
+        }
+        let media_type = validate_media_type(text_property(configuration, "media-type")?.unwrap_or_default())?;
+        let topic_type = text_property(configuration, "topic-type")?.unwrap_or_default();
+        let expiration_date = text_property(configuration, "expiration-date")?
+            .map(|expiration_date| validate_expiration_date(&expiration_date, Utc::now()))
+            .transpose()?;
+        let max_subscribers = uint_property(configuration, "max-subscribers")?.unwrap_or(u32::MAX);
+        let observe_check = validate_observe_check(uint_property(configuration, "observer-check")?.unwrap_or(DEFAULT_OBSERVE_CHECK))?;
+
+        self.topic_name = topic_name;
+        self.resource_type = resource_type;
+        self.set_media_type(media_type);
+        self.set_topic_type(topic_type);
+        self.set_expiration_date(expiration_date);
+        self.set_max_subscribers(max_subscribers);
+        self.set_observe_check(observe_check);
+        Ok(())
+    }
+    /// Applies a json merge patch (RFC 7396) to the topic configuration, as done with PATCH and iPATCH.
+    ///
+    /// Properties in the patch replace the stored ones, properties set to null return to their defaults.
+    /// The patched configuration is validated like a replacement, so nothing is changed if it is rejected.
+    pub fn patch_configuration(&mut self, patch: &Value, collection_name: &str) -> Result<(), ConfigurationError> {
+        let patch = patch.as_object()
+            .ok_or_else(|| ConfigurationError::Invalid("merge patch must be an object".to_string()))?;
+        check_known_properties(patch)?;
+        if patch.get("topic-data").is_some_and(Value::is_null) {
+            return Err(ConfigurationError::Immutable("topic-data can't be removed".to_string()));
+        }
+
+        let mut configuration = self.get_configuration(collection_name);
+        if let Some(properties) = configuration.as_object_mut() {
+            for (name, value) in patch {
+                if value.is_null() {
+                    properties.remove(name);
+                } else {
+                    properties.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        self.replace_configuration(&configuration, collection_name)
+    }
+    /// Checks whether the topic configuration matches every property of the filter.
+    /// Properties left out of the filter match any value. The expiration-date matches at the same instant in any
+    /// time zone, and numbers match by value, so 3.0 matches a stored 3.
+    pub fn matches_filter(&self, filter: &Map<String, Value>, collection_name: &str) -> bool {
+        let configuration = self.get_configuration(collection_name);
+        filter.iter().all(|(name, value)| match (name.as_str(), value) {
+            ("topic-data", Value::String(topic_data)) => data_uri_from_path(topic_data, collection_name) == self.topic_data,
+            ("expiration-date", Value::String(expiration_date)) => {
+                parse_expiration_date(expiration_date).ok().is_some_and(|expiration_date| self.get_expiration_date() == Some(expiration_date))
+            }
+            ("max-subscribers" | "observer-check", Value::Number(number)) => {
+                configuration.get(name).and_then(Value::as_f64).is_some_and(|stored| number.as_f64() == Some(stored))
+            }
+            _ => configuration.get(name) == Some(value),
+        })
+    }
+    /// Checks that a topic-data given in a configuration points to the current data resource of the topic.
+    /// Both the plain data uri and the full path under the collection are accepted.
+    fn check_topic_data(&self, topic_data: &str, collection_name: &str) -> Result<(), ConfigurationError> {
+        if data_uri_from_path(topic_data, collection_name) != self.topic_data {
+            return Err(ConfigurationError::Immutable("topic-data can't be changed".to_string()));
+        }
+        Ok(())
+    }
 
 }
 ///Topic collection as struct. Represents a collection of topics in the broker.
@@ -229,6 +523,11 @@ impl TopicCollection {
         &self.topics
     }
 
+    /// Returns the topics in the topic collection as mutable.
+    pub fn get_topics_mut(&mut self) -> &mut HashMap<String, Topic> {
+        &mut self.topics
+    }
+
     /// Returns reference to dataresource from path
     pub fn get_data_from_path(&self, path: String) -> &DataResource {
         println!("{}",path);
@@ -245,7 +544,7 @@ impl TopicCollection {
 
     /// Returns data value from path, if path doesn't exist or no value, return empty String
     pub fn get_data_value_from_path(&self, path: String) -> String {
-        self.data.get(&path).clone().unwrap().get_data().to_string()
+        String::from_utf8_lossy(self.data.get(&path).unwrap().get_data()).to_string()
     }
 
     /// Sets the name of the topic collection.
@@ -271,14 +570,50 @@ impl TopicCollection {
 
     //Additional functionality
 
+    /// Creates a new topic in the collection from the json configuration sent at topic creation, returning its uri.
+    ///
+    /// The topic uri and, unless the client requested one, the data uri are generated so that they are unique
+    /// in the collection. A requested topic-data that is already in use is rejected.
+    pub fn create_topic(&mut self, configuration: &Value) -> Result<String, ConfigurationError> {
+        let mut topic = Topic::from_configuration(configuration, &self.name)?;
+
+        if configuration.get("topic-data").is_some() {
+            if self.is_uri_taken(topic.get_topic_data()) {
+                return Err(ConfigurationError::Conflict(format!("topic-data {} is already in use", topic.get_topic_data())));
+            }
+        } else {
+            topic.set_topic_data(self.generate_unique_uri(&[]));
+        }
+        let topic_uri = self.generate_unique_uri(&[topic.get_topic_data()]);
+        topic.set_topic_uri(topic_uri.clone());
+
+        self.add_topic(topic);
+        Ok(topic_uri)
+    }
+
+    /// Checks whether a uri is already used by a topic or a data resource in the collection.
+    pub fn is_uri_taken(&self, uri: &str) -> bool {
+        self.topics.contains_key(uri) || self.topics.values().any(|topic| topic.get_topic_data() == uri)
+    }
+
+    /// Generates a uri that isn't used by any topic or data resource in the collection, nor listed in reserved.
+    pub fn generate_unique_uri(&self, reserved: &[&str]) -> String {
+        loop {
+            let uri = generate_uri();
+            if !self.is_uri_taken(&uri) && !reserved.contains(&uri.as_str()) {
+                return uri;
+            }
+        }
+    }
+
     /// Adds a topic to the topic collection.
     pub fn add_topic(&mut self, topic: Topic) {
         self.topics.insert(topic.get_topic_uri().to_string(), topic);
     }
 
-    /// Removes a topic from the topic collection by its uri.
-    pub fn remove_topic(&mut self, topic_uri: &str) {
-        self.topics.remove(topic_uri);
+    /// Removes a topic from the topic collection by its uri, returning it if it existed.
+    pub fn remove_topic(&mut self, topic_uri: &str) -> Option<Topic> {
+        self.topics.remove(topic_uri)
     }
 
     /// Finds a topic in the topic collection by its URI.
@@ -301,6 +636,25 @@ impl TopicCollection {
         self.topics.get_mut(topic_uri)
     }
 
+    /// Removes the topics that have expired at the given time and returns them.
+    pub fn remove_expired_topics(&mut self, now: DateTime<Utc>) -> Vec<Topic> {
+        let expired_uris: Vec<String> = self.topics.values()
+            .filter(|topic| topic.is_expired(now))
+            .map(|topic| topic.get_topic_uri())
+            .collect();
+        expired_uris.iter().filter_map(|topic_uri| self.topics.remove(topic_uri)).collect()
+    }
+
+    /// Finds the topics whose configuration matches the given partial topic configuration, as done with FETCH.
+    ///
+    /// The filter is a json object with any subset of the configuration properties, an empty filter matches every topic.
+    pub fn filter_topics(&self, filter: &Value) -> Result<Vec<&Topic>, ConfigurationError> {
+        let filter = filter.as_object()
+            .ok_or_else(|| ConfigurationError::Invalid("filter must be an object".to_string()))?;
+        check_known_properties(filter)?;
+        Ok(self.topics.values().filter(|topic| topic.matches_filter(filter, &self.name)).collect())
+    }
+
     /// Finds a topic in the topic collection by its name.
     pub fn find_topic_by_name(&self, topic_name: &str) -> Option<&Topic> {
         self.topics.values().find(|topic| topic.get_topic_name() == topic_name)
@@ -314,14 +668,71 @@ impl TopicCollection {
     /// Changes current data in selected path, aka publishing new data if dataresource exists
     pub fn update_data_value(&mut self, path: String, value: String) {
         if let Some(data) = self.data.get_mut(&path) {
-            data.data = value;
+            data.data = value.into_bytes();
         }
     }
 }
+/// Observe sequence numbers are the 24 least significant bits of a counter (RFC 7641 section 4.4).
+const OBSERVE_SEQUENCE_MODULUS: u32 = 1 << 24;
+
+/// Identifies an observation: the client endpoint and the token of its Observe registration (RFC 7641 section 3.1).
+pub type ObservationKey = (SocketAddr, Vec<u8>);
+
+/// A subscriber of a data resource, made by an Observe registration.
+///
+/// The token of the registration is echoed in every notification, and notifications are sent from the
+/// broker socket the registration arrived on, so that they come from the address the client sent it to.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    /// The address of the client endpoint.
+    addr: SocketAddr,
+    /// The token of the Observe registration.
+    token: Vec<u8>,
+    /// The broker socket the registration arrived on.
+    socket: Arc<UdpSocket>,
+    /// Whether the subscriber asked for confirmable notifications.
+    confirmable: bool,
+    /// The conditional attributes of the registration, which decide when the subscriber is notified.
+    conditions: Conditions,
+}
+/// Subscriber implementation.
+impl Subscriber {
+    pub fn new(addr: SocketAddr, token: Vec<u8>, socket: Arc<UdpSocket>, confirmable: bool, conditions: Conditions) -> Self {
+        Subscriber { addr, token, socket, confirmable, conditions }
+    }
+    /// Get the address of the client endpoint.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Get the token of the Observe registration.
+    pub fn get_token(&self) -> &[u8] {
+        &self.token
+    }
+    /// Get the broker socket the registration arrived on.
+    pub fn get_socket(&self) -> &Arc<UdpSocket> {
+        &self.socket
+    }
+    /// Check if the subscriber asked for confirmable notifications.
+    pub fn is_confirmable(&self) -> bool {
+        self.confirmable
+    }
+    /// Get the conditions of the subscriber's observation.
+    pub fn get_conditions(&self) -> &Conditions {
+        &self.conditions
+    }
+    /// Get the conditions of the subscriber's observation, to evaluate them.
+    pub fn get_conditions_mut(&mut self) -> &mut Conditions {
+        &mut self.conditions
+    }
+    /// Get the key of the subscriber's observation.
+    pub fn get_key(&self) -> ObservationKey {
+        (self.addr, self.token.clone())
+    }
+}
 /// DataResource struct and its implemented methods. Represents a data resource in the broker.
 /// 
 /// Data_uri is the same as hosting topic configuration's (aka Topic) topic_data.
-/// Data_resource hosts the data and holds vector of subscribers.
+/// Data_resource hosts the data and holds the registry of subscribers, one per observation.
 #[derive(Default)]
 pub struct DataResource {
     /// The URI of the data resource. Used for data publishing/retrieving and subscribing.
@@ -330,10 +741,18 @@ pub struct DataResource {
     parent_topic_uri: String,
     /// The type of the resource associated with the data resource.
     resource_type: String,
-    /// The subscribers of the data resource.
-    subscribers: Vec<SocketAddr>,
+    /// The subscribers of the data resource, by the client endpoint and token of their observation.
+    subscribers: HashMap<ObservationKey, Subscriber>,
     /// The data of the data resource.
-    data: String,
+    data: Vec<u8>,
+    /// The Content-Format id the data was published with, if the publication had one.
+    content_format: Option<u16>,
+    /// When the data was published, None until the first publication.
+    published_at: Option<DateTime<Utc>>,
+    /// Max-Age in seconds the publisher gave the data. Data without one doesn't go stale.
+    max_age: Option<u32>,
+    /// The Observe value of the latest registration response or notification of the data resource.
+    observe_sequence: u32,
 }
 /// DataResource implementation.
 /// 
@@ -348,8 +767,12 @@ impl DataResource {
             data_uri: generate_uri(),
             parent_topic_uri: String::from("yolo"),
             resource_type: String::from("core.ps.data"),
-            subscribers: Vec::new(),
-            data: String::new(),
+            subscribers: HashMap::new(),
+            data: Vec::new(),
+            content_format: None,
+            published_at: None,
+            max_age: None,
+            observe_sequence: 0,
         }
     //Getters and setters
     }
@@ -366,13 +789,35 @@ impl DataResource {
         &self.resource_type
     }
     /// Get the subscribers of the data resource.
-    pub fn get_subscribers(&self) -> &Vec<SocketAddr> {
-        &self.subscribers
+    pub fn get_subscribers(&self) -> impl Iterator<Item = &Subscriber> {
+        self.subscribers.values()
+    }
+    /// Get the subscribers of the data resource as mutable.
+    pub fn get_subscribers_mut(&mut self) -> impl Iterator<Item = &mut Subscriber> {
+        self.subscribers.values_mut()
+    }
+    /// Get the number of observations of the data resource.
+    pub fn get_subscriber_count(&self) -> usize {
+        self.subscribers.len()
     }
     /// Get the data of the data resource.
-    pub fn get_data(&self) -> &String {
+    pub fn get_data(&self) -> &Vec<u8> {
         &self.data
     }
+    /// Get the Content-Format id the data was published with.
+    pub fn get_content_format(&self) -> Option<u16> {
+        self.content_format
+    }
+    /// Seconds the data stays fresh after the given time, 0 once it's stale.
+    /// None if the publisher gave no Max-Age.
+    pub fn remaining_max_age(&self, now: DateTime<Utc>) -> Option<u32> {
+        let age = self.published_at.map_or(0, |published_at| (now - published_at).num_seconds().max(0));
+        self.max_age.map(|max_age| u32::try_from(i64::from(max_age) - age).unwrap_or(0))
+    }
+    /// Check whether the data is older than the Max-Age it was published with at the given time.
+    pub fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        self.remaining_max_age(now) == Some(0)
+    }
     /// Set the URI of the data resource.
     pub fn set_data_uri(&mut self, data_uri: String) {
         self.data_uri = data_uri;
@@ -386,19 +831,327 @@ impl DataResource {
         self.resource_type = resource_type;
     }
     /// Set the subscribers of the data resource.
-    pub fn set_subscribers(&mut self, subscribers: Vec<SocketAddr>) {
-        self.subscribers = subscribers;
+    pub fn set_subscribers(&mut self, subscribers: Vec<Subscriber>) {
+        self.subscribers = subscribers.into_iter().map(|subscriber| (subscriber.get_key(), subscriber)).collect();
     }
-    /// Set the data of the data resource.
-    pub fn set_data(&mut self, data: String) {
+    /// Store a publication: the data, the Content-Format id and Max-Age it was published with, and when it was published.
+    pub fn publish(&mut self, data: Vec<u8>, content_format: Option<u16>, max_age: Option<u32>, published_at: DateTime<Utc>) {
         self.data = data;
+        self.content_format = content_format;
+        self.max_age = max_age;
+        self.published_at = Some(published_at);
+    }
+    /// Remove the data of the data resource along with its Content-Format and freshness.
+    pub fn clear_data(&mut self) {
+        self.data = Vec::new();
+        self.content_format = None;
+        self.max_age = None;
+        self.published_at = None;
+    }
+    /// Advance the Observe sequence number and return it, wrapping around after 2^24 - 1.
+    /// Every registration response and notification takes the next number, so each subscriber sees them increase.
+    pub fn next_observe_sequence(&mut self) -> u32 {
+        self.observe_sequence = (self.observe_sequence + 1) % OBSERVE_SEQUENCE_MODULUS;
+        self.observe_sequence
+    }
+    /// Check whether the observation is registered with the data resource.
+    pub fn is_subscriber(&self, key: &ObservationKey) -> bool {
+        self.subscribers.contains_key(key)
+    }
+    /// Add a subscriber to the data resource. A subscriber with the same client endpoint and token
+    /// re-registers its observation: the existing entry is refreshed instead of adding a second one.
+    /// Returns true if the observation is new.
+    pub fn add_subscriber(&mut self, subscriber: Subscriber) -> bool {
+        self.subscribers.insert(subscriber.get_key(), subscriber).is_none()
+    }
+    /// Remove the observation with the client endpoint and token from the data resource.
+    /// Other observations of the same client endpoint stay. Returns false if there was no such observation.
+    pub fn remove_subscriber(&mut self, key: &ObservationKey) -> bool {
+        self.subscribers.remove(key).is_some()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A topic in the "ps" collection with only the mandatory properties and the data uri "door".
+    fn door() -> Topic {
+        Topic::from_configuration(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "door"}), "ps").unwrap()
+    }
+
+    #[test]
+    fn test_replace_configuration_resets_left_out_properties() {
+        let mut topic = door();
+        topic.replace_configuration(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "media-type": "application/json", "max-subscribers": 3}), "ps").unwrap();
+        assert_eq!(topic.get_media_type(), "application/json");
+        assert_eq!(topic.get_max_subscribers(), 3);
+
+        topic.replace_configuration(&json!({"topic-name": "front door", "resource-type": "core.ps.conf"}), "ps").unwrap();
+        assert_eq!(topic.get_topic_name(), "front door");
+        assert_eq!(topic.get_media_type(), "");
+        assert_eq!(topic.get_max_subscribers(), u32::MAX);
+        assert_eq!(topic.get_observe_check(), DEFAULT_OBSERVE_CHECK);
+    }
+
+    #[test]
+    fn test_replace_configuration_requires_mandatory_properties() {
+        let mut topic = door();
+        for configuration in [json!({"resource-type": "core.ps.conf"}), json!({"topic-name": "", "resource-type": "core.ps.conf"}),
+                              json!({"topic-name": "door"}), json!({"topic-name": "door", "resource-type": "core.ps.coll"})] {
+            let result = topic.replace_configuration(&configuration, "ps");
+            assert!(matches!(result, Err(ConfigurationError::Invalid(_))), "{} was accepted", configuration);
+        }
+        assert_eq!(topic.get_topic_name(), "door");
+    }
+
+    #[test]
+    fn test_replace_configuration_keeps_topic_data() {
+        let mut topic = door();
+        // The data uri can be repeated, plain or as a path under the collection
+        topic.replace_configuration(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "door"}), "ps").unwrap();
+        topic.replace_configuration(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "ps/data/door"}), "ps").unwrap();
+
+        let result = topic.replace_configuration(&json!({"topic-name": "window", "resource-type": "core.ps.conf", "topic-data": "window"}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Immutable(_))));
+        assert_eq!(topic.get_topic_name(), "door");
+        assert_eq!(topic.get_topic_data(), "door");
+    }
+
+    #[test]
+    fn test_patch_configuration_removes_null_properties() {
+        let mut topic = door();
+        topic.patch_configuration(&json!({"media-type": "application/json", "topic-type": "sensor"}), "ps").unwrap();
+        assert_eq!(topic.get_media_type(), "application/json");
+        assert_eq!(topic.get_topic_type(), "sensor");
+
+        // null returns a property to its default, the ones left out of the patch stay
+        topic.patch_configuration(&json!({"media-type": null}), "ps").unwrap();
+        assert_eq!(topic.get_media_type(), "");
+        assert_eq!(topic.get_topic_type(), "sensor");
+        assert_eq!(topic.get_topic_name(), "door");
+    }
+
+    #[test]
+    fn test_patch_configuration_keeps_mandatory_and_immutable_properties() {
+        let mut topic = door();
+        let result = topic.patch_configuration(&json!({"topic-data": null}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Immutable(_))));
+        let result = topic.patch_configuration(&json!({"topic-data": "window"}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Immutable(_))));
+        let result = topic.patch_configuration(&json!({"topic-name": null}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+
+        // A rejected patch changes nothing, even the properties that were valid
+        let result = topic.patch_configuration(&json!({"topic-type": "sensor", "max-subscribers": -1}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+        assert_eq!(topic.get_topic_type(), "");
+        assert_eq!(topic.get_topic_data(), "door");
+        assert_eq!(topic.get_topic_name(), "door");
     }
-    /// Add a subscriber to the data resource.
-    pub fn add_subscriber(&mut self, subscriber: SocketAddr) {
-        self.subscribers.push(subscriber);
+
+    #[test]
+    fn test_matches_filter_compares_given_properties() {
+        let mut topic = door();
+        topic.patch_configuration(&json!({"media-type": "application/json", "max-subscribers": 3}), "ps").unwrap();
+        let filter = |filter: Value| topic.matches_filter(filter.as_object().unwrap(), "ps");
+
+        assert!(filter(json!({})));
+        assert!(filter(json!({"media-type": "application/json", "max-subscribers": 3})));
+        assert!(!filter(json!({"media-type": "application/json", "max-subscribers": 4})));
+        assert!(!filter(json!({"max-subscribers": "3"})));
+        // numbers match by value
+        assert!(filter(json!({"max-subscribers": 3.0})));
+        assert!(!filter(json!({"max-subscribers": 3.5})));
+        // topic-data matches as a plain data uri or a path under the collection
+        assert!(filter(json!({"topic-data": "door"})));
+        assert!(filter(json!({"topic-data": "ps/data/door"})));
+        assert!(!filter(json!({"topic-data": "other/data/door"})));
+    }
+
+    #[test]
+    fn test_matches_filter_compares_expiration_instants() {
+        let mut topic = door();
+        topic.patch_configuration(&json!({"expiration-date": "2099-05-01T12:00:00Z"}), "ps").unwrap();
+        let filter = |filter: Value| topic.matches_filter(filter.as_object().unwrap(), "ps");
+        assert!(filter(json!({"expiration-date": "2099-05-01T12:00:00Z"})));
+        assert!(filter(json!({"expiration-date": "2099-05-01T14:00:00+02:00"})));
+        assert!(!filter(json!({"expiration-date": "2099-05-01T14:00:00Z"})));
+        assert!(!filter(json!({"expiration-date": "not a date"})));
+        assert!(!door().matches_filter(json!({"expiration-date": "2099-05-01T12:00:00Z"}).as_object().unwrap(), "ps"));
+    }
+
+    #[test]
+    fn test_filter_topics() {
+        let mut collection = TopicCollection::new("ps".to_string());
+        collection.create_topic(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-type": "sensor"})).unwrap();
+        collection.create_topic(&json!({"topic-name": "window", "resource-type": "core.ps.conf", "topic-type": "sensor"})).unwrap();
+        collection.create_topic(&json!({"topic-name": "lamp", "resource-type": "core.ps.conf", "topic-type": "actuator"})).unwrap();
+
+        let names = |filter: Value| {
+            let mut names: Vec<_> = collection.filter_topics(&filter).unwrap().iter().map(|topic| topic.get_topic_name().to_string()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(json!({})), ["door", "lamp", "window"]);
+        assert_eq!(names(json!({"topic-type": "sensor"})), ["door", "window"]);
+        assert_eq!(names(json!({"topic-type": "sensor", "topic-name": "window"})), ["window"]);
+        assert!(names(json!({"topic-type": "camera"})).is_empty());
+
+        assert!(matches!(collection.filter_topics(&json!({"colour": "red"})), Err(ConfigurationError::Invalid(_))));
+        assert!(matches!(collection.filter_topics(&json!(["topic-type"])), Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_from_configuration_validates_properties() {
+        let valid = json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "ps/data/door",
+                           "media-type": "Text/Plain; Charset=UTF-8", "topic-type": "sensor", "max-subscribers": 10, "observer-check": 60});
+        let topic = Topic::from_configuration(&valid, "ps").unwrap();
+        assert!(topic.half_created);
+        assert_eq!(topic.get_topic_data(), "door");
+        assert_eq!(topic.get_content_format(), Some(0));
+        assert_eq!(topic.get_observe_check(), 60);
+
+        for (name, value) in [("colour", json!("red")), ("topic-name", json!(1)), ("topic-data", json!("ps/data/a/b")),
+                              ("topic-data", json!("")), ("media-type", json!("image/png")), ("max-subscribers", json!(-1)),
+                              ("max-subscribers", json!(u64::from(u32::MAX) + 1)), ("observer-check", json!(0)),
+                              ("observer-check", json!(1.5))] {
+            let mut configuration = valid.clone();
+            configuration[name] = value.clone();
+            let result = Topic::from_configuration(&configuration, "ps");
+            assert!(matches!(result, Err(ConfigurationError::Invalid(_))), "{} {} was accepted", name, value);
+        }
+        assert!(matches!(Topic::from_configuration(&json!("door"), "ps"), Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_expiration_date_parsing() {
+        let mut topic = door();
+        topic.patch_configuration(&json!({"expiration-date": "2099-05-01T14:00:00+02:00"}), "ps").unwrap();
+        let expiration_date = topic.get_expiration_date().unwrap();
+        assert_eq!(expiration_date, DateTime::parse_from_rfc3339("2099-05-01T12:00:00Z").unwrap());
+        // Given back in UTC
+        assert_eq!(topic.get_configuration("ps")["expiration-date"], "2099-05-01T12:00:00Z");
+
+        for expiration_date in ["2099-05-01", "2099-05-01 12:00:00", "tomorrow"] {
+            let result = topic.patch_configuration(&json!({"expiration-date": expiration_date}), "ps");
+            assert!(matches!(result, Err(ConfigurationError::Invalid(_))), "{} was accepted", expiration_date);
+        }
+        topic.patch_configuration(&json!({"expiration-date": null}), "ps").unwrap();
+        assert_eq!(topic.get_expiration_date(), None);
+    }
+
+    #[test]
+    fn test_past_expiration_date_is_rejected() {
+        let mut collection = TopicCollection::new("ps".to_string());
+        let result = collection.create_topic(&json!({"topic-name": "expired", "resource-type": "core.ps.conf", "expiration-date": "2020-01-01T00:00:00Z"}));
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+        assert!(collection.get_topics().is_empty());
+
+        let mut topic = door();
+        let result = topic.patch_configuration(&json!({"expiration-date": "2020-01-01T00:00:00Z"}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+        let result = topic.replace_configuration(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "expiration-date": "2020-01-01T00:00:00Z"}), "ps");
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+        assert_eq!(topic.get_expiration_date(), None);
+
+        let now = Utc::now();
+        let result = validate_expiration_date(&now.to_rfc3339(), now);
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_remove_expired_topics() {
+        let mut collection = TopicCollection::new("ps".to_string());
+        let expiring = collection.create_topic(&json!({"topic-name": "expiring", "resource-type": "core.ps.conf", "expiration-date": "2099-05-01T12:00:00Z"})).unwrap();
+        let lasting = collection.create_topic(&json!({"topic-name": "lasting", "resource-type": "core.ps.conf"})).unwrap();
+        let expiration_date = collection.find_topic_by_uri(&expiring).unwrap().get_expiration_date().unwrap();
+
+        assert!(collection.remove_expired_topics(expiration_date - chrono::Duration::seconds(1)).is_empty());
+        let expired = collection.remove_expired_topics(expiration_date);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].get_topic_uri(), expiring);
+        assert!(collection.find_topic_by_uri(&expiring).is_none());
+        assert!(collection.find_topic_by_uri(&lasting).is_some());
     }
-    /// Remove a subscriber from the data resource.
-    pub fn remove_subscriber(&mut self, subscriber: SocketAddr) {
-        self.subscribers.retain(|s| s != &subscriber);
+
+    #[test]
+    fn test_create_topic_rejects_data_uri_collisions() {
+        let mut collection = TopicCollection::new("ps".to_string());
+        let topic_uri = collection.create_topic(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "door"})).unwrap();
+        assert!(collection.is_uri_taken("door"));
+        assert!(collection.is_uri_taken(&topic_uri));
+
+        let result = collection.create_topic(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": "ps/data/door"}));
+        assert!(matches!(result, Err(ConfigurationError::Conflict(_))));
+        // Neither can a data uri take the uri of a topic
+        let result = collection.create_topic(&json!({"topic-name": "door", "resource-type": "core.ps.conf", "topic-data": topic_uri}));
+        assert!(matches!(result, Err(ConfigurationError::Conflict(_))));
+        assert_eq!(collection.get_topics().len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_generated_uris_are_unique() {
+        let mut collection = TopicCollection::new("ps".to_string());
+        for index in 0..100 {
+            collection.create_topic(&json!({"topic-name": format!("topic {}", index), "resource-type": "core.ps.conf"})).unwrap();
+        }
+        let mut uris: Vec<String> = collection.get_topics().values()
+            .flat_map(|topic| [topic.get_topic_uri(), topic.get_topic_data().to_string()])
+            .collect();
+        assert!(uris.iter().all(|uri| uri.len() == 6 && uri.chars().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase())));
+        uris.sort();
+        uris.dedup();
+        assert_eq!(uris.len(), 200);
+
+        assert!(!collection.is_uri_taken(&collection.generate_unique_uri(&[])));
+    }
+
+    #[test]
+    fn test_content_format_of_media_type() {
+        assert_eq!(content_format_of_media_type("application/json"), Some(50));
+        assert_eq!(content_format_of_media_type("Application/SenML+CBOR"), Some(112));
+        assert_eq!(content_format_of_media_type("text/plain; charset=utf-8"), Some(0));
+        assert_eq!(content_format_of_media_type("application/yaml"), None);
+        assert_eq!(content_format_of_media_type(""), None);
+    }
+
+    #[test]
+    fn test_data_keeps_the_content_format_it_was_published_with() {
+        let mut topic = door();
+        assert_eq!(topic.get_data_content_format(), None);
+        topic.patch_configuration(&json!({"media-type": "application/cbor"}), "ps").unwrap();
+        assert_eq!(topic.get_data_content_format(), Some(60));
+
+        topic.patch_configuration(&json!({"media-type": null}), "ps").unwrap();
+        topic.get_data_resource().publish(vec![0xa0], Some(60), None, Utc::now());
+        topic.patch_configuration(&json!({"media-type": "application/json"}), "ps").unwrap();
+        assert_eq!(topic.get_data_content_format(), Some(60));
+    }
+
+    #[test]
+    fn test_data_goes_stale_after_max_age() {
+        let published_at = Utc::now();
+        let mut data = DataResource::new();
+        data.publish(b"21.5".to_vec(), None, Some(60), published_at);
+        assert_eq!(data.remaining_max_age(published_at), Some(60));
+        assert_eq!(data.remaining_max_age(published_at + chrono::Duration::seconds(45)), Some(15));
+        assert!(!data.is_stale(published_at + chrono::Duration::seconds(59)));
+        assert!(data.is_stale(published_at + chrono::Duration::seconds(60)));
+        assert_eq!(data.remaining_max_age(published_at + chrono::Duration::seconds(90)), Some(0));
+
+        // Without a Max-Age the data stays fresh
+        data.publish(b"22".to_vec(), None, None, published_at);
+        assert_eq!(data.remaining_max_age(published_at + chrono::Duration::days(1)), None);
+        assert!(!data.is_stale(published_at + chrono::Duration::days(1)));
+    }
+
+    #[test]
+    fn test_observe_sequence_wraps_around() {
+        let mut data = DataResource::new();
+        assert_eq!(data.next_observe_sequence(), 1);
+        assert_eq!(data.next_observe_sequence(), 2);
+        data.observe_sequence = OBSERVE_SEQUENCE_MODULUS - 2;
+        assert_eq!(data.next_observe_sequence(), OBSERVE_SEQUENCE_MODULUS - 1);
+        assert_eq!(data.next_observe_sequence(), 0);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::confirmable;
+use async_trait::async_trait;
+use coap::server::{Listener, Responder, TransportRequestSender};
+use coap_lite::Packet;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// A UDP listener for the coap server that shares its socket with the broker.
+///
+/// The broker sends notifications from the socket the Observe registration arrived on, so they come from
+/// the address the client registered with. Acknowledgements and resets of the confirmable notifications
+/// come back to the same socket, and are passed to the waiting sender instead of the server.
+pub struct SharedUdpListener {
+    socket: Arc<UdpSocket>,
+}
+
+impl SharedUdpListener {
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        SharedUdpListener { socket }
+    }
+
+    async fn receive_loop(self, sender: TransportRequestSender) -> std::io::Result<()> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // An earlier datagram to a closed port, not a problem of the socket itself
+                Err(e) if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused) => continue,
+                Err(e) => return Err(e),
+            };
+            if let Ok(packet) = Packet::from_bytes(&buf[..len]) {
+                if confirmable::answer(from, &packet) {
+                    continue;
+                }
+            }
+            let responder = Arc::new(SharedUdpResponder { socket: self.socket.clone(), address: from });
+            sender.send((buf[..len].to_vec(), responder))
+                .map_err(|_| Error::other("server channel error"))?;
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for SharedUdpListener {
+    async fn listen(self: Box<Self>, sender: TransportRequestSender) -> std::io::Result<JoinHandle<std::io::Result<()>>> {
+        Ok(tokio::spawn(self.receive_loop(sender)))
+    }
+}
+
+/// Sends the server's responses from the socket the request arrived on.
+struct SharedUdpResponder {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+}
+
+#[async_trait]
+impl Responder for SharedUdpResponder {
+    async fn respond(&self, response: Vec<u8>) {
+        if let Err(e) = self.socket.send_to(&response, self.address).await {
+            eprintln!("Failed to respond to {}: {}", self.address, e);
+        }
+    }
+
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
@@ -1,5 +1,5 @@
 use coap::UdpCoAPClient;
-use coap_lite::{CoapRequest, CoapResponse, Packet, RequestType as Method};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, Packet, RequestType as Method};
 use std::io::{self, Write};
 use std::error::Error;
 use std::io::{ErrorKind, Error as IoError};
@@ -30,7 +30,7 @@ async fn handle_command() {
         println!("1. topic name/uri/datauri discovery");
         println!("2. subscribe <Topic_data_URI>");
         println!("3. unsubscribe <Topic_data_URI>");
-        println!("4. create topic <TopicName>");
+        println!("4. create topic <TopicName> [InitialValue]");
         println!("5. update topic data: PUT <Topic_data_URI> <Payload>");
         println!("6. delete topic configuration: DELETE <TopicURI>");
         println!("7. multicast broker discovery");
@@ -39,6 +39,10 @@ async fn handle_command() {
         println!("10. topic-configuration discovery");
         println!("11. topic-data discovery");
         println!("12. topic collection discovery");
+        println!("13. read topic configuration <TopicURI>");
+        println!("14. create topic collection <CollectionName>");
+        println!("15. delete topic collection <CollectionName>");
+        println!("16. delete topic data <DataUri>");
         println!("");
 
         io::stdout().flush().unwrap();
@@ -58,7 +62,10 @@ async fn handle_command() {
                 let _ = subscription(topic_data_uri, 1).await;
             },
             ["4", topic_name] | ["create topic", topic_name]=>{
-                create_topic(topic_name).await;
+                create_topic(topic_name, None).await;
+            },
+            ["4", topic_name, initial_value] => {
+                create_topic(topic_name, Some(initial_value)).await;
             },
             ["5", topic_data_uri, payload] | ["PUT", topic_data_uri, payload] => {
                 let _ = update_topic(topic_data_uri, payload).await;
@@ -83,6 +90,18 @@ async fn handle_command() {
             },
             ["12"] | ["topic", "collection", "discovery"] => {
                 let _ = topic_collection_discovery().await;
+            },
+            ["13", topic_uri] | ["read", "configuration", topic_uri] => {
+                let _ = read_topic_configuration(topic_uri).await;
+            },
+            ["14", collection_name] | ["create", "collection", collection_name] => {
+                let _ = create_collection(collection_name).await;
+            },
+            ["15", collection_name] | ["delete", "collection", collection_name] => {
+                let _ = delete_collection(collection_name).await;
+            },
+            ["16", topic_data_uri] | ["delete", "data", topic_data_uri] => {
+                let _ = delete_topic_data(topic_data_uri).await;
             }
             _ => println!("Invalid command. Please enter one from the list of commands."),
         }
@@ -95,7 +114,8 @@ async fn topic_collection_discovery() {
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.coll");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.coll".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -115,7 +135,8 @@ async fn topic_data_discovery() {
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.data");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.data".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -137,7 +158,8 @@ async fn multicast_discovery_uri_query(){
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
 
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps".to_vec());
 
     //segment is ipv6 segment for multicast, need to be called on all segments we want to use, but in our case ipv4 is used so "0" is enough for now
     let segment: u8 = 0;
@@ -174,7 +196,8 @@ async fn broker_discovery_uri_query(){
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -208,7 +231,7 @@ fn server_error(e: &IoError) {
 
 /// Function that handles deleting a topic configuration. Sends a DELETE request to the server.
 async fn delete_topic(topic_uri: &str) -> Result<(), Box<dyn Error>> {
-    let url = format!("{}/{}", "coap://".to_owned()+GLOBAL_URL,topic_uri);
+    let url = format!("{}/ps/{}", "coap://".to_owned()+GLOBAL_URL,topic_uri);
     println!("Client request: {}", url);
 
     match UdpCoAPClient::delete(&url).await {
@@ -282,6 +305,16 @@ async fn listen_for_messages(socket: Arc<UdpSocket>) {
             Ok((len, src)) => {
                 // Successfully received a message
                 let packet = Packet::from_bytes(&buf[..len]).unwrap();
+
+                // Acknowledge confirmable notifications, otherwise the broker's observer check removes this subscription
+                if packet.header.get_type() == MessageType::Confirmable {
+                    let mut ack = Packet::new();
+                    ack.header.set_type(MessageType::Acknowledgement);
+                    ack.header.code = MessageClass::Empty;
+                    ack.header.message_id = packet.header.message_id;
+                    let _ = socket.send_to(&ack.to_bytes().unwrap(), src).await;
+                }
+
                 let request = CoapRequest::from_packet(packet, src);
                 let clone = request.clone();
                 let msg = String::from_utf8(clone.message.payload).unwrap();
@@ -342,11 +375,16 @@ async fn discovery(url: &str) {
     }
 }
 /// Function that handles the creation of a topic. Name of the topic is mandatory parameter. 
+/// With an initial value the topic is published to on creation and is fully created right away.
 /// Sends a POST request to the server.
-async fn create_topic(topic_name: &str) {
+async fn create_topic(topic_name: &str, initial_value: Option<&str>) {
     let url = "coap://".to_owned()+GLOBAL_URL+"/ps"; 
     let resource_type="core.ps.conf";
-    let payload = json!({"topic-name": topic_name, "resource-type": resource_type}).to_string();
+    let mut payload = json!({"topic-name": topic_name, "resource-type": resource_type});
+    if let Some(initial_value) = initial_value {
+        payload["initialize"] = json!(initial_value);
+    }
+    let payload = payload.to_string();
     let payload_bytes = payload.into_bytes();
     
 
@@ -380,13 +418,75 @@ async fn read_latest_topic_data(topic_data: &str) -> Result<(), Box<dyn Error>>
         }
     }
 }
+/// Create a new topic collection. Sends a POST request to the broker root.
+async fn create_collection(collection_name: &str) -> Result<(), Box<dyn Error>> {
+    let url = format!("coap://{}/", GLOBAL_URL);
+    let payload = json!({"collection-name": collection_name}).to_string();
+
+    match UdpCoAPClient::post(&url, payload.into_bytes()).await {
+        Ok(response) => {
+            server_reply(response);
+            Ok(())
+        }
+        Err(e) => {
+            server_error(&e);
+            Err(Box::new(e))
+        }
+    }
+}
+/// Delete the data of a topic, the topic returns to half-created state. Sends a DELETE request to the topic data resource.
+async fn delete_topic_data(topic_data_uri: &str) -> Result<(), Box<dyn Error>> {
+    let url = format!("coap://{}/ps/data/{}", GLOBAL_URL, topic_data_uri);
+
+    match UdpCoAPClient::delete(&url).await {
+        Ok(response) => {
+            server_reply(response);
+            Ok(())
+        }
+        Err(e) => {
+            server_error(&e);
+            Err(Box::new(e))
+        }
+    }
+}
+/// Delete a topic collection and all of its topics. Sends a DELETE request to the collection.
+async fn delete_collection(collection_name: &str) -> Result<(), Box<dyn Error>> {
+    let url = format!("coap://{}/{}", GLOBAL_URL, collection_name);
+
+    match UdpCoAPClient::delete(&url).await {
+        Ok(response) => {
+            server_reply(response);
+            Ok(())
+        }
+        Err(e) => {
+            server_error(&e);
+            Err(Box::new(e))
+        }
+    }
+}
+/// Read the configuration of a topic. Sends a GET request to the topic configuration resource.
+async fn read_topic_configuration(topic_uri: &str) -> Result<(), Box<dyn Error>> {
+    let url = format!("coap://{}/ps/{}", GLOBAL_URL, topic_uri);
+
+    match UdpCoAPClient::get(&url).await {
+        Ok(response) => {
+            server_reply(response);
+            Ok(())
+        }
+        Err(e) => {
+            server_error(&e);
+            Err(Box::new(e))
+        }
+    }
+}
 /// Topic configuration discovery.
 async fn topic_configuration_discovery() {
     println!("Topic configuration discovery start");
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.conf");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.conf".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
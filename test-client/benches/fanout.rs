@@ -0,0 +1,162 @@
+//! Publishes to a topic with 10,000 subscribers and measures how long it takes until every one of them
+//! has received its notification, first with non-confirmable and then with confirmable notifications.
+//! Last, one endpoint acknowledges its confirmable notifications only after a delay, to show how much such a
+//! slow subscriber holds up the others.
+//!
+//! Needs a running broker, like the integration tests: `cargo bench --bench fanout`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType as Method, ResponseType};
+use common::{request, BROKER_ADDR};
+use std::error::Error;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Number of observations of the topic.
+const SUBSCRIBERS: usize = 10_000;
+/// Client endpoints the observations are spread over, each holding observations with different tokens.
+const ENDPOINTS: usize = 100;
+/// Publications measured with each kind of notification.
+const PUBLICATIONS: usize = 10;
+/// Longest wait for the notifications of one publication.
+const FAN_OUT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the slow endpoint takes to acknowledge a notification, short of the broker's first retransmission.
+const SLOW_ACK_DELAY: Duration = Duration::from_secs(1);
+
+const COLLECTION: &str = "fanout-bench";
+const DATA_PATH: &str = "fanout-bench/data/load";
+
+/// Message ids of the registrations, unique so the broker doesn't take a registration for a retransmission.
+static NEXT_MESSAGE_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Registers an observation for every token of the endpoint, with the given Uri-Query.
+async fn register(socket: &UdpSocket, query: &str) -> Result<(), Box<dyn Error>> {
+    let tokens_per_endpoint = SUBSCRIBERS / ENDPOINTS;
+    for token in 0..tokens_per_endpoint {
+        let mut request = common::new_request(Method::Get, DATA_PATH, b"", None);
+        request.message.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        request.message.header.message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+        request.message.set_token((token as u16).to_be_bytes().to_vec());
+        request.message.set_observe_value(0);
+        socket.send_to(&request.message.to_bytes()?, BROKER_ADDR).await?;
+    }
+    let mut buf = [0u8; 1280];
+    for _ in 0..tokens_per_endpoint {
+        let (len, _) = timeout(Duration::from_secs(10), socket.recv_from(&mut buf)).await??;
+        let response = Packet::from_bytes(&buf[..len])?;
+        assert!(response.get_observe_value().is_some(), "registration failed: {}", String::from_utf8_lossy(&response.payload));
+    }
+    Ok(())
+}
+
+/// Receives notifications on an endpoint until every one of its observations got the expected payload,
+/// acknowledging the confirmable ones after the given delay.
+async fn receive(socket: Arc<UdpSocket>, expected: Vec<u8>, ack_delay: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut remaining = SUBSCRIBERS / ENDPOINTS;
+    let mut buf = [0u8; 1280];
+    while remaining > 0 {
+        let (len, src) = timeout(FAN_OUT_TIMEOUT, socket.recv_from(&mut buf)).await??;
+        let notification = Packet::from_bytes(&buf[..len]).map_err(|e| format!("{:?}", e))?;
+        if notification.header.get_type() == MessageType::Confirmable {
+            let mut acknowledgement = Packet::new();
+            acknowledgement.header.set_type(MessageType::Acknowledgement);
+            acknowledgement.header.code = MessageClass::Empty;
+            acknowledgement.header.message_id = notification.header.message_id;
+            let acknowledgement = acknowledgement.to_bytes().map_err(|e| format!("{:?}", e))?;
+            if ack_delay.is_zero() {
+                socket.send_to(&acknowledgement, src).await?;
+            } else {
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(ack_delay).await;
+                    let _ = socket.send_to(&acknowledgement, src).await;
+                });
+            }
+        }
+        if notification.payload == expected {
+            remaining -= 1;
+        }
+    }
+    Ok(())
+}
+
+/// Waits until every endpoint received its notifications with the expected payload.
+async fn receive_all(endpoints: &[Arc<UdpSocket>], expected: &[u8]) -> Result<(), Box<dyn Error>> {
+    let receivers: Vec<_> = endpoints.iter()
+        .map(|socket| tokio::spawn(receive(socket.clone(), expected.to_vec(), Duration::ZERO)))
+        .collect();
+    for receiver in receivers {
+        receiver.await?.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Publishes PUBLICATIONS times and prints how long the fan-out to the subscribers on the endpoints took.
+/// The slow endpoints acknowledge after SLOW_ACK_DELAY, they aren't measured but are waited for before the next publication.
+async fn measure(name: &str, endpoints: &[Arc<UdpSocket>], slow_endpoints: &[Arc<UdpSocket>]) -> Result<(), Box<dyn Error>> {
+    let mut durations = Vec::new();
+    for publication in 0..PUBLICATIONS {
+        let payload = format!("{}-{}", name, publication);
+        let slow_receivers: Vec<_> = slow_endpoints.iter()
+            .map(|socket| tokio::spawn(receive(socket.clone(), payload.as_bytes().to_vec(), SLOW_ACK_DELAY)))
+            .collect();
+        let start = Instant::now();
+        let response = request(Method::Put, DATA_PATH, payload.as_bytes(), None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Changed));
+        receive_all(endpoints, payload.as_bytes()).await?;
+        durations.push(start.elapsed());
+        if !slow_receivers.is_empty() {
+            for receiver in slow_receivers {
+                receiver.await?.map_err(|e| e.to_string())?;
+            }
+            // The last acknowledgements of the slow endpoints are still on their way
+            tokio::time::sleep(SLOW_ACK_DELAY).await;
+        }
+    }
+
+    durations.sort();
+    let median = durations[durations.len() / 2];
+    let subscribers = endpoints.len() * (SUBSCRIBERS / ENDPOINTS);
+    println!(
+        "{:<28} {} subscribers: min {:>8.1?}  median {:>8.1?}  max {:>8.1?}  ({:.0} notifications/s)",
+        name, subscribers, durations[0], median, durations[durations.len() - 1],
+        subscribers as f64 / median.as_secs_f64(),
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Post, "", format!("{{\"collection-name\": \"{}\"}}", COLLECTION).as_bytes(), None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created), "is the broker running?");
+    let configuration = b"{\"topic-name\": \"load\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"load\", \"initialize\": \"0\"}";
+    request(Method::Post, COLLECTION, configuration, None).await?;
+
+    let mut endpoints = Vec::new();
+    for _ in 0..ENDPOINTS {
+        endpoints.push(Arc::new(UdpSocket::bind("127.0.0.1:0").await?));
+    }
+
+    for socket in &endpoints {
+        register(socket, "con=0").await?;
+    }
+    measure("non-confirmable", &endpoints, &[]).await?;
+
+    // Registering again with the same tokens switches the observations to confirmable notifications
+    for socket in &endpoints {
+        register(socket, "con").await?;
+    }
+    measure("confirmable", &endpoints, &[]).await?;
+
+    // The observations of the first endpoint take SLOW_ACK_DELAY to acknowledge and hold their in-flight permits meanwhile
+    let (slow_endpoints, fast_endpoints) = endpoints.split_at(1);
+    measure("confirmable, slow endpoint", fast_endpoints, slow_endpoints).await?;
+
+    // The final notifications are confirmable too and have to be acknowledged
+    request(Method::Delete, COLLECTION, b"", None).await?;
+    receive_all(&endpoints, b"Topic collection deleted").await
+}
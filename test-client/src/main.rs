@@ -1,5 +1,5 @@
 use coap::UdpCoAPClient;
-use coap_lite::{CoapRequest, CoapResponse, Packet, RequestType as Method};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, Packet, RequestType as Method};
 use std::io::{self, Write};
 use std::error::Error;
 use std::io::{ErrorKind, Error as IoError};
@@ -79,7 +79,8 @@ async fn topic_collection_discovery() {
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.coll");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.coll".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -98,7 +99,8 @@ async fn topic_data_discovery() {
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.data");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.data".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -120,7 +122,8 @@ async fn multicast_discovery_uri_query(){
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
 
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps".to_vec());
 
     //segment is ipv6 segment for multicast, need to be called on all segments we want to use, but in our case ipv4 is used so "0" is enough for now
     let segment: u8 = 0;
@@ -157,7 +160,8 @@ async fn broker_discovery_uri_query(){
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
@@ -191,7 +195,7 @@ fn server_error(e: &IoError) {
 
 /// Function that handles deleting a topic configuration. Sends a DELETE request to the server.
 async fn delete_topic(topic_uri: &str) -> Result<(), Box<dyn Error>> {
-    let url = format!("{}/{}", "coap://".to_owned()+GLOBAL_URL,topic_uri);
+    let url = format!("{}/ps/{}", "coap://".to_owned()+GLOBAL_URL,topic_uri);
 
     match UdpCoAPClient::delete(&url).await {
         Ok(response) => {
@@ -263,6 +267,16 @@ async fn listen_for_messages(socket: Arc<UdpSocket>) {
             Ok((len, src)) => {
                 // Successfully received a message
                 let packet = Packet::from_bytes(&buf[..len]).unwrap();
+
+                // Acknowledge confirmable notifications, otherwise the broker's observer check removes this subscription
+                if packet.header.get_type() == MessageType::Confirmable {
+                    let mut ack = Packet::new();
+                    ack.header.set_type(MessageType::Acknowledgement);
+                    ack.header.code = MessageClass::Empty;
+                    ack.header.message_id = packet.header.message_id;
+                    let _ = socket.send_to(&ack.to_bytes().unwrap(), src).await;
+                }
+
                 let request = CoapRequest::from_packet(packet, src);
                 let clone = request.clone();
                 let msg = String::from_utf8(clone.message.payload).unwrap();
@@ -364,7 +378,8 @@ async fn topic_configuration_discovery() {
     let addr = GLOBAL_URL;
     let mut client: UdpCoAPClient = UdpCoAPClient::new_udp(addr).await.unwrap();
     let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-    request.set_path(".well-known/core?rt=core.ps.conf");
+    request.set_path(".well-known/core");
+    request.message.add_option(CoapOption::UriQuery, b"rt=core.ps.conf".to_vec());
 
     let response = UdpCoAPClient::perform_request(&mut client, request).await.unwrap();
     let pay = String::from_utf8(response.message.payload);
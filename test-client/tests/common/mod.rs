@@ -0,0 +1,191 @@
+//! Helpers shared by the tests that talk to the broker directly over CoAP.
+#![allow(dead_code)]
+
+use coap_lite::{CoapOption, CoapRequest, MessageClass, MessageType, Packet, RequestType as Method, ResponseType};
+use coap_lite::option_value::OptionValueU16;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration, Instant};
+
+pub static BROKER_ADDR: &str = "127.0.0.1:5683";
+
+/// The Content-Format numbers, shared with the broker.
+#[path = "../../../broker/src/content_format.rs"]
+pub mod content_format;
+
+/// Message ids of the observers' requests, unique so the broker doesn't take a request for a retransmission.
+static NEXT_MESSAGE_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Builds a confirmable request, with a Content-Format option if one is given.
+pub fn new_request(method: Method, path: &str, payload: &[u8], content_format: Option<u16>) -> CoapRequest<SocketAddr> {
+    let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+    request.set_method(method);
+    request.set_path(path);
+    request.message.payload = payload.to_vec();
+    if let Some(content_format) = content_format {
+        request.message.add_option_as(CoapOption::ContentFormat, OptionValueU16(content_format));
+    }
+    request
+}
+
+/// Sends the request to the broker from a new socket and returns the response.
+pub async fn send(mut request: CoapRequest<SocketAddr>) -> Result<Packet, Box<dyn Error>> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    request.message.header.message_id = socket.local_addr()?.port();
+    request.message.set_token(request.message.header.message_id.to_be_bytes().to_vec());
+    socket.send_to(&request.message.to_bytes()?, BROKER_ADDR).await?;
+
+    let mut buf = [0u8; 1280];
+    let (len, _) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await??;
+    Ok(Packet::from_bytes(&buf[..len])?)
+}
+
+/// Sends a single request to the broker and returns its response.
+pub async fn request(method: Method, path: &str, payload: &[u8], content_format: Option<u16>) -> Result<Packet, Box<dyn Error>> {
+    send(new_request(method, path, payload, content_format)).await
+}
+
+/// Creates a collection for a test, runs the test and deletes the collection with all its topics again,
+/// so the broker is left as it was for the other tests. The collection is deleted even if the test panics.
+pub async fn with_collection<F, Fut>(name: &str, test: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: std::future::Future<Output = Result<(), Box<dyn Error>>>,
+{
+    let payload = format!("{{\"collection-name\": \"{}\"}}", name);
+    let response = request(Method::Post, "", payload.as_bytes(), None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+
+    let _cleanup = CollectionCleanup(name.to_string());
+    test(name.to_string()).await
+}
+
+/// Deletes a test's collection when dropped, also while a failed assertion unwinds.
+/// A drop can't await, so the request goes out on a blocking socket.
+struct CollectionCleanup(String);
+
+impl Drop for CollectionCleanup {
+    fn drop(&mut self) {
+        let mut request = new_request(Method::Delete, &self.0, b"", None);
+        let deleted = std::net::UdpSocket::bind("127.0.0.1:0").and_then(|socket| {
+            request.message.header.message_id = socket.local_addr()?.port();
+            request.message.set_token(request.message.header.message_id.to_be_bytes().to_vec());
+            socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+            socket.send_to(&request.message.to_bytes().map_err(std::io::Error::other)?, BROKER_ADDR)?;
+            socket.recv_from(&mut [0u8; 1280])
+        });
+        if let Err(e) = deleted {
+            eprintln!("Failed to delete test collection {}: {}", self.0, e);
+        }
+    }
+}
+
+/// Creates a fully created topic with the given data uri and initial data in the collection,
+/// and returns the path of its data.
+pub async fn create_topic(collection: &str, data_uri: &str, initial: &str) -> Result<String, Box<dyn Error>> {
+    let configuration = format!("{{\"topic-name\": \"{0}\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"{0}\", \"initialize\": \"{1}\"}}", data_uri, initial);
+    let response = request(Method::Post, collection, configuration.as_bytes(), None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+    Ok(format!("{}/data/{}", collection, data_uri))
+}
+
+/// Checks whether a notification with Observe value v2 received at t2 is newer than the one with v1 received at t1,
+/// as RFC 7641 section 3.4 has clients do. The values are 24-bit and wrap around.
+pub fn is_fresh((v1, t1): (u32, Instant), (v2, t2): (u32, Instant)) -> bool {
+    (v1 < v2 && v2 - v1 < 1 << 23) || (v1 > v2 && v1 - v2 > 1 << 23) || t2 > t1 + Duration::from_secs(128)
+}
+
+/// An observing client endpoint, which keeps its socket open for the notifications.
+pub struct Observer {
+    socket: UdpSocket,
+    /// The address the observer sends its requests to, the broker's or that of a relay in front of it.
+    broker: SocketAddr,
+    /// Observe value and arrival time of the freshest registration response or notification.
+    latest: Option<(u32, Instant)>,
+}
+
+impl Observer {
+    pub async fn new() -> Result<Self, Box<dyn Error>> {
+        Self::with_broker(BROKER_ADDR.parse()?).await
+    }
+
+    /// An observer that talks to the broker through the given address.
+    pub async fn with_broker(broker: SocketAddr) -> Result<Self, Box<dyn Error>> {
+        Ok(Observer { socket: UdpSocket::bind("127.0.0.1:0").await?, broker, latest: None })
+    }
+
+    /// Takes in a registration response or notification like a client following RFC 7641 would.
+    /// Returns false if the message is older than one already seen and has to be dropped.
+    pub fn accept(&mut self, message: &Packet) -> bool {
+        let observe = message.get_observe_value().expect("no Observe option").expect("invalid Observe option");
+        let received = (observe, Instant::now());
+        if self.latest.is_some_and(|latest| !is_fresh(latest, received)) {
+            return false;
+        }
+        self.latest = Some(received);
+        true
+    }
+
+    /// Registers an observation of the data resource with the given token and returns the broker's response.
+    pub async fn register(&self, path: &str, token: &[u8]) -> Result<Packet, Box<dyn Error>> {
+        self.observe(path, &[], token, 0).await
+    }
+
+    /// Deregisters the observation of the data resource with the given token and returns the broker's response.
+    pub async fn deregister(&self, path: &str, token: &[u8]) -> Result<Packet, Box<dyn Error>> {
+        self.observe(path, &[], token, 1).await
+    }
+
+    /// Sends an Observe request with the given Uri-Query options and token, and returns the broker's response.
+    pub async fn observe(&self, path: &str, queries: &[&str], token: &[u8], observe: u32) -> Result<Packet, Box<dyn Error>> {
+        let mut request = new_request(Method::Get, path, b"", None);
+        for query in queries {
+            request.message.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        }
+        request.message.header.message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+        request.message.set_token(token.to_vec());
+        request.message.set_observe_value(observe);
+        self.socket.send_to(&request.message.to_bytes()?, self.broker).await?;
+        Ok(self.receive().await?.0)
+    }
+
+    /// Receives the notifications arriving within a short while, by their token.
+    pub async fn receive_all(&self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let mut tokens = Vec::new();
+        let mut buf = [0u8; 1280];
+        while let Ok(received) = timeout(Duration::from_millis(300), self.socket.recv_from(&mut buf)).await {
+            let (len, _) = received?;
+            tokens.push(Packet::from_bytes(&buf[..len])?.get_token().to_vec());
+        }
+        tokens.sort();
+        Ok(tokens)
+    }
+
+    /// Answers a confirmable notification with an empty acknowledgement or reset.
+    pub async fn answer(&self, notification: &Packet, message_type: MessageType) -> Result<(), Box<dyn Error>> {
+        let mut answer = Packet::new();
+        answer.header.set_type(message_type);
+        answer.header.code = MessageClass::Empty;
+        answer.header.message_id = notification.header.message_id;
+        self.socket.send_to(&answer.to_bytes()?, self.broker).await?;
+        Ok(())
+    }
+
+    /// Waits a while for the next message from the broker, None if none arrived.
+    pub async fn receive_within(&self, wait: Duration) -> Result<Option<Packet>, Box<dyn Error>> {
+        let mut buf = [0u8; 1280];
+        match timeout(wait, self.socket.recv_from(&mut buf)).await {
+            Ok(received) => Ok(Some(Packet::from_bytes(&buf[..received?.0])?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Waits for the next message from the broker, and where it came from.
+    pub async fn receive(&self) -> Result<(Packet, SocketAddr), Box<dyn Error>> {
+        let mut buf = [0u8; 1280];
+        let (len, src) = timeout(Duration::from_secs(2), self.socket.recv_from(&mut buf)).await??;
+        Ok((Packet::from_bytes(&buf[..len])?, src))
+    }
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use coap_lite::{MessageClass, RequestType as Method, ResponseType};
+use common::{create_topic, request, with_collection, Observer};
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+/// Publishes a value and returns the payload of the notification it led to, if any.
+async fn publish(observer: &Observer, path: &str, value: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    request(Method::Put, path, value.as_bytes(), None).await?;
+    Ok(observer.receive_within(Duration::from_millis(300)).await?.map(|notification| notification.payload))
+}
+
+#[tokio::test]
+async fn test_step_holds_back_small_changes() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-step-test", |collection| async move {
+        let data_path = create_topic(&collection, "temperature", "20").await?;
+        let observer = Observer::new().await?;
+        observer.observe(&data_path, &["st=0.5"], &[1], 0).await?;
+
+        assert_eq!(publish(&observer, &data_path, "20.2").await?, None);
+        // The step is measured from the last notified value, 20
+        assert_eq!(publish(&observer, &data_path, "20.6").await?, Some(b"20.6".to_vec()));
+        assert_eq!(publish(&observer, &data_path, "20.9").await?, None);
+        assert_eq!(publish(&observer, &data_path, "20.0").await?, Some(b"20.0".to_vec()));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_thresholds_notify_crossings() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-threshold-test", |collection| async move {
+        let data_path = create_topic(&collection, "level", "20").await?;
+        let observer = Observer::new().await?;
+        observer.observe(&data_path, &["gt=25", "lt=10"], &[1], 0).await?;
+
+        assert_eq!(publish(&observer, &data_path, "24").await?, None);
+        assert_eq!(publish(&observer, &data_path, "26").await?, Some(b"26".to_vec()));
+        assert_eq!(publish(&observer, &data_path, "30").await?, None);
+        assert_eq!(publish(&observer, &data_path, "24").await?, Some(b"24".to_vec()));
+        assert_eq!(publish(&observer, &data_path, "9").await?, Some(b"9".to_vec()));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_pmin_defers_notification_of_latest_data() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-pmin-test", |collection| async move {
+        let data_path = create_topic(&collection, "counter", "0").await?;
+        let observer = Observer::new().await?;
+        let registered = Instant::now();
+        observer.observe(&data_path, &["pmin=1"], &[1], 0).await?;
+
+        assert_eq!(publish(&observer, &data_path, "1").await?, None);
+        assert_eq!(publish(&observer, &data_path, "2").await?, None);
+        let notification = observer.receive_within(Duration::from_secs(2)).await?.expect("deferred notification wasn't sent");
+        assert!(registered.elapsed() >= Duration::from_secs(1));
+        assert_eq!(notification.payload, b"2");
+        assert_eq!(observer.receive_within(Duration::from_millis(1500)).await?.map(|notification| notification.payload), None);
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_epmin_defers_evaluation() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-epmin-test", |collection| async move {
+        let data_path = create_topic(&collection, "pressure", "1000").await?;
+        let observer = Observer::new().await?;
+        observer.observe(&data_path, &["epmin=1", "st=5"], &[1], 0).await?;
+
+        // Evaluated once epmin has passed since the registration, against the latest data
+        assert_eq!(publish(&observer, &data_path, "1010").await?, None);
+        assert_eq!(publish(&observer, &data_path, "1002").await?, None);
+        assert_eq!(observer.receive_within(Duration::from_millis(1500)).await?.map(|notification| notification.payload), None);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(publish(&observer, &data_path, "1006").await?, Some(b"1006".to_vec()));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_pmax_resends_latest_data() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-pmax-test", |collection| async move {
+        let data_path = create_topic(&collection, "heartbeat", "alive").await?;
+        let observer = Observer::new().await?;
+        let response = observer.observe(&data_path, &["pmax=1"], &[1], 0).await?;
+        let registered = response.get_observe_value().expect("no Observe option")?;
+
+        for _ in 0..2 {
+            let notification = observer.receive_within(Duration::from_secs(2)).await?.expect("no notification after pmax");
+            assert_eq!(notification.header.code, MessageClass::Response(ResponseType::Content));
+            assert_eq!(notification.payload, b"alive");
+            assert!(notification.get_observe_value().expect("no Observe option")? > registered);
+        }
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_invalid_conditional_attributes_are_bad_request() -> Result<(), Box<dyn Error>> {
+    with_collection("conditions-invalid-test", |collection| async move {
+        let data_path = create_topic(&collection, "invalid", "0").await?;
+        let observer = Observer::new().await?;
+        for queries in [&["pmin=5", "pmax=2"][..], &["st=abc"], &["st=0"], &["pmin=-1"], &["epmin=3", "epmax=3"]] {
+            let response = observer.observe(&data_path, queries, &[1], 0).await?;
+            assert_eq!(response.header.code, MessageClass::Response(ResponseType::BadRequest), "{:?} was accepted", queries);
+        }
+        Ok(())
+    }).await
+}
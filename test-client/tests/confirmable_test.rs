@@ -0,0 +1,161 @@
+mod common;
+
+use coap_lite::{MessageClass, MessageType, Packet, RequestType as Method, ResponseType};
+use common::{create_topic, request, with_collection, Observer, BROKER_ADDR};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+/// Longest wait for a retransmission: the first one follows within ACK_TIMEOUT * ACK_RANDOM_FACTOR = 3 seconds.
+const RETRANSMISSION_WAIT: Duration = Duration::from_secs(5);
+
+/// A loopback relay between one observer and the broker that loses the first confirmable messages
+/// from the broker and the first acknowledgements from the observer.
+struct LossyRelay {
+    /// The address the observer sends to in place of the broker.
+    addr: SocketAddr,
+    /// The number of confirmable messages the broker sent, including the lost ones.
+    confirmables: Arc<AtomicUsize>,
+}
+
+impl LossyRelay {
+    async fn start(lost_confirmables: usize, lost_acknowledgements: usize) -> Result<Self, Box<dyn Error>> {
+        let observer_side = UdpSocket::bind("127.0.0.1:0").await?;
+        let broker_side = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = observer_side.local_addr()?;
+        let confirmables = Arc::new(AtomicUsize::new(0));
+        let counter = confirmables.clone();
+
+        tokio::spawn(async move {
+            let (mut lost_confirmables, mut lost_acknowledgements) = (lost_confirmables, lost_acknowledgements);
+            let mut observer = None;
+            let (mut from_observer, mut from_broker) = ([0u8; 1280], [0u8; 1280]);
+            loop {
+                tokio::select! {
+                    Ok((len, src)) = observer_side.recv_from(&mut from_observer) => {
+                        observer = Some(src);
+                        let message = &from_observer[..len];
+                        if is_type(message, MessageType::Acknowledgement) && lost_acknowledgements > 0 {
+                            lost_acknowledgements -= 1;
+                            continue;
+                        }
+                        let _ = broker_side.send_to(message, BROKER_ADDR).await;
+                    }
+                    Ok((len, _)) = broker_side.recv_from(&mut from_broker) => {
+                        let message = &from_broker[..len];
+                        if is_type(message, MessageType::Confirmable) {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            if lost_confirmables > 0 {
+                                lost_confirmables -= 1;
+                                continue;
+                            }
+                        }
+                        if let Some(observer) = observer {
+                            let _ = observer_side.send_to(message, observer).await;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(LossyRelay { addr, confirmables })
+    }
+
+    fn confirmables(&self) -> usize {
+        self.confirmables.load(Ordering::SeqCst)
+    }
+}
+
+fn is_type(message: &[u8], message_type: MessageType) -> bool {
+    Packet::from_bytes(message).is_ok_and(|packet| packet.header.get_type() == message_type)
+}
+
+#[tokio::test]
+async fn test_lost_notification_is_retransmitted() -> Result<(), Box<dyn Error>> {
+    with_collection("con-lost-notification-test", |collection| async move {
+        let data_path = create_topic(&collection, "lossy", "0").await?;
+
+        let relay = LossyRelay::start(1, 0).await?;
+        let observer = Observer::with_broker(relay.addr).await?;
+        let response = observer.observe(&data_path, &["con"], &[5], 0).await?;
+        assert!(response.get_observe_value().is_some());
+
+        request(Method::Put, &data_path, b"1", None).await?;
+        let notification = observer.receive_within(RETRANSMISSION_WAIT).await?.expect("no notification");
+        assert_eq!(notification.header.get_type(), MessageType::Confirmable);
+        assert_eq!(notification.get_token(), [5]);
+        assert_eq!(notification.payload, b"1");
+        assert_eq!(relay.confirmables(), 2, "the lost notification wasn't retransmitted");
+        observer.answer(&notification, MessageType::Acknowledgement).await?;
+
+        // The acknowledged observation goes on
+        request(Method::Put, &data_path, b"2", None).await?;
+        let notification = observer.receive_within(Duration::from_secs(2)).await?.expect("no notification");
+        assert_eq!(notification.payload, b"2");
+        observer.answer(&notification, MessageType::Acknowledgement).await?;
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_lost_acknowledgement_is_answered_again() -> Result<(), Box<dyn Error>> {
+    with_collection("con-lost-ack-test", |collection| async move {
+        let data_path = create_topic(&collection, "lossy", "0").await?;
+
+        let relay = LossyRelay::start(0, 1).await?;
+        let observer = Observer::with_broker(relay.addr).await?;
+        observer.observe(&data_path, &["con=1"], &[6], 0).await?;
+
+        request(Method::Put, &data_path, b"1", None).await?;
+        let notification = observer.receive_within(Duration::from_secs(2)).await?.expect("no notification");
+        observer.answer(&notification, MessageType::Acknowledgement).await?;
+
+        // The acknowledgement was lost, so the same message comes again
+        let retransmission = observer.receive_within(RETRANSMISSION_WAIT).await?.expect("no notification");
+        assert_eq!(retransmission.header.message_id, notification.header.message_id);
+        assert_eq!(retransmission.payload, b"1");
+        observer.answer(&retransmission, MessageType::Acknowledgement).await?;
+        assert!(observer.receive_within(RETRANSMISSION_WAIT).await?.is_none(), "acknowledged notification was retransmitted");
+
+        let response = observer.observe(&data_path, &[], &[6], 1).await?;
+        assert_eq!(response.payload, b"Unsubscribed successfully");
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_reset_ends_observation() -> Result<(), Box<dyn Error>> {
+    with_collection("con-reset-test", |collection| async move {
+        let data_path = create_topic(&collection, "reset", "0").await?;
+
+        let observer = Observer::new().await?;
+        observer.observe(&data_path, &["con"], &[7], 0).await?;
+
+        request(Method::Put, &data_path, b"1", None).await?;
+        let notification = observer.receive_within(Duration::from_secs(2)).await?.expect("no notification");
+        assert_eq!(notification.header.get_type(), MessageType::Confirmable);
+        observer.answer(&notification, MessageType::Reset).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        request(Method::Put, &data_path, b"2", None).await?;
+        assert!(observer.receive_within(Duration::from_millis(500)).await?.is_none(), "reset observation was notified");
+        let response = observer.observe(&data_path, &[], &[7], 1).await?;
+        assert_eq!(response.payload, b"Subscriber not found");
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_invalid_con_query_is_bad_request() -> Result<(), Box<dyn Error>> {
+    with_collection("con-query-test", |collection| async move {
+        let data_path = create_topic(&collection, "query", "0").await?;
+
+        let observer = Observer::new().await?;
+        let response = observer.observe(&data_path, &["con=yes"], &[8], 0).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::BadRequest));
+        assert!(response.get_observe_value().is_none());
+        Ok(())
+    }).await
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType as Method, ResponseType};
+use coap_lite::option_value::OptionValueU32;
+use common::content_format::JSON_CONTENT_FORMAT;
+use common::{new_request, request, send, with_collection};
+use std::error::Error;
+use tokio::time::{sleep, Duration};
+
+/// Checks the response code and that the response explains the error in its payload.
+fn assert_error(response: &Packet, expected: ResponseType) {
+    assert_eq!(response.header.code, MessageClass::Response(expected));
+    assert!(!response.payload.is_empty(), "error response without diagnostic payload");
+    println!("Diagnostic payload: {}", String::from_utf8_lossy(&response.payload));
+}
+
+#[tokio::test]
+async fn test_malformed_payloads_are_bad_request() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Post, "ps", b"{\"topic-name\": ", None).await?;
+    assert_error(&response, ResponseType::BadRequest);
+
+    let response = request(Method::Post, "ps", b"{\"topic-name\": \"weather\", \"resource-type\": \"core.ps.conf\", \"max-subscribers\": -1}", None).await?;
+    assert_error(&response, ResponseType::BadRequest);
+
+    let response = request(Method::Post, "ps", b"{\"topic-name\": \"weather\", \"resource-type\": \"core.ps.conf\", \"expiration-date\": \"2020-01-01T00:00:00Z\"}", None).await?;
+    assert_error(&response, ResponseType::BadRequest);
+
+    let response = request(Method::Post, "", b"[]", None).await?;
+    assert_error(&response, ResponseType::BadRequest);
+
+    let response = request(Method::Fetch, "ps", b"not json", None).await?;
+    assert_error(&response, ResponseType::BadRequest);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_observe_is_bad_request() -> Result<(), Box<dyn Error>> {
+    let mut request = new_request(Method::Get, "ps/data/anything", b"", None);
+    request.message.set_observe_value(5);
+    let response = send(request).await?;
+    assert_error(&response, ResponseType::BadRequest);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_paths_are_not_found() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Get, "ps/data/unknown/deeper", b"", None).await?;
+    assert_error(&response, ResponseType::NotFound);
+
+    let response = request(Method::Get, "ps/unknown-topic", b"", None).await?;
+    assert_error(&response, ResponseType::NotFound);
+
+    let response = request(Method::Put, "ps/data/unknown-data", b"1", None).await?;
+    assert_error(&response, ResponseType::NotFound);
+
+    let response = request(Method::Post, "unknown-collection", b"{\"topic-name\": \"weather\", \"resource-type\": \"core.ps.conf\"}", None).await?;
+    assert_error(&response, ResponseType::NotFound);
+
+    let response = request(Method::Delete, "ps/unknown-topic", b"", None).await?;
+    assert_error(&response, ResponseType::NotFound);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_collection_is_not_deleted() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Delete, "ps", b"", None).await?;
+    assert_error(&response, ResponseType::Forbidden);
+
+    let response = request(Method::Get, "ps", b"", None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wrong_methods_are_method_not_allowed() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Post, "ps/some-topic", b"{}", None).await?;
+    assert_error(&response, ResponseType::MethodNotAllowed);
+
+    let response = request(Method::Put, "ps", b"{}", None).await?;
+    assert_error(&response, ResponseType::MethodNotAllowed);
+
+    let response = request(Method::Patch, "ps/data/some-data", b"{}", None).await?;
+    assert_error(&response, ResponseType::MethodNotAllowed);
+
+    let response = request(Method::Get, "", b"", None).await?;
+    assert_error(&response, ResponseType::MethodNotAllowed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wrong_formats_are_unsupported_content_format() -> Result<(), Box<dyn Error>> {
+    // application/xml is neither json nor CBOR
+    let response = request(Method::Post, "ps", b"<topic/>", Some(41)).await?;
+    assert_error(&response, ResponseType::UnsupportedContentFormat);
+
+    let response = request(Method::Post, "", b"{\"collection-name\": \"xml\"}", Some(41)).await?;
+    assert_error(&response, ResponseType::UnsupportedContentFormat);
+
+    // Publications have to match the media-type of the topic
+    with_collection("errors-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"json only\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"json-only\", \"media-type\": \"application/json\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+
+        // application/cbor
+        let response = request(Method::Put, &format!("{}/data/json-only", collection), &[0xa0], Some(60)).await?;
+        assert_error(&response, ResponseType::UnsupportedContentFormat);
+
+        let response = request(Method::Put, &format!("{}/data/json-only", collection), b"{}", Some(JSON_CONTENT_FORMAT)).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_stale_data_is_not_found() -> Result<(), Box<dyn Error>> {
+    with_collection("freshness-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"short lived\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"short-lived\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+
+        let data_path = format!("{}/data/short-lived", collection);
+        let mut publication = new_request(Method::Put, &data_path, b"21", None);
+        publication.message.add_option_as(CoapOption::MaxAge, OptionValueU32(1));
+        let response = send(publication).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+
+        // Fresh data comes with the Max-Age it has left
+        let response = request(Method::Get, &data_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        let max_age = response.get_first_option_as::<OptionValueU32>(CoapOption::MaxAge).unwrap()?;
+        assert!(max_age.0 <= 1);
+
+        sleep(Duration::from_millis(1500)).await;
+        let response = request(Method::Get, &data_path, b"", None).await?;
+        assert_error(&response, ResponseType::NotFound);
+        Ok(())
+    }).await
+}
@@ -0,0 +1,270 @@
+mod common;
+
+use coap_lite::{CoapOption, MessageClass, MessageType, RequestType as Method, ResponseType};
+use coap_lite::option_value::OptionValueU32;
+use common::{create_topic, is_fresh, new_request, request, send, with_collection, Observer, BROKER_ADDR};
+use std::error::Error;
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+#[tokio::test]
+async fn test_notifications_echo_token_from_broker_socket() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-token-test", |collection| async move {
+        let data_path = create_topic(&collection, "counter", "0").await?;
+
+        let observer = Observer::new().await?;
+        let token = [0xca, 0xfe, 0x01];
+        let response = observer.register(&data_path, &token).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        assert_eq!(response.get_token(), token);
+        assert!(response.get_observe_value().is_some());
+
+        let response = request(Method::Put, &data_path, b"1", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Changed));
+
+        let (notification, src) = observer.receive().await?;
+        assert_eq!(src, BROKER_ADDR.parse::<SocketAddr>()?, "notification didn't come from the broker's listening socket");
+        assert_eq!(notification.get_token(), token);
+        assert_eq!(notification.header.get_type(), MessageType::NonConfirmable);
+        assert_eq!(notification.payload, b"1");
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_final_notification_echoes_token() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-removal-test", |collection| async move {
+        let data_path = create_topic(&collection, "doomed", "0").await?;
+
+        let observer = Observer::new().await?;
+        let token = [0x42];
+        observer.register(&data_path, &token).await?;
+
+        let response = request(Method::Delete, &data_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Deleted));
+
+        let (notification, src) = observer.receive().await?;
+        assert_eq!(src, BROKER_ADDR.parse::<SocketAddr>()?);
+        assert_eq!(notification.header.code, MessageClass::Response(ResponseType::NotFound));
+        assert_eq!(notification.get_token(), token);
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_notifications_pass_freshness_checks() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-sequence-test", |collection| async move {
+        let data_path = create_topic(&collection, "sequence", "0").await?;
+
+        let mut first = Observer::new().await?;
+        let response = first.register(&data_path, &[1]).await?;
+        assert!(first.accept(&response));
+
+        for value in 1..=5 {
+            // Each registration is answered with a newer value than the notifications before it
+            let mut late = Observer::new().await?;
+            let response = late.register(&data_path, &[2]).await?;
+            assert!(late.accept(&response));
+
+            let payload = value.to_string();
+            request(Method::Put, &data_path, payload.as_bytes(), None).await?;
+            for observer in [&mut first, &mut late] {
+                let (notification, _) = observer.receive().await?;
+                assert_eq!(notification.payload, payload.as_bytes());
+                assert!(observer.accept(&notification), "notification {} was dropped as stale", value);
+            }
+        }
+        Ok(())
+    }).await
+}
+
+#[test]
+fn test_freshness_check_follows_wrap_around() {
+    let now = Instant::now();
+    assert!(is_fresh((1, now), (2, now)));
+    assert!(!is_fresh((2, now), (1, now)));
+    assert!(!is_fresh((5, now), (5, now)));
+    // After 2^24 - 1 the broker's sequence continues at 0
+    assert!(is_fresh(((1 << 24) - 1, now), (0, now)));
+    assert!(!is_fresh((0, now), ((1 << 24) - 1, now)));
+}
+
+#[tokio::test]
+async fn test_reregistration_refreshes_observation() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-refresh-test", |collection| async move {
+        let data_path = create_topic(&collection, "refresh", "0").await?;
+
+        let observer = Observer::new().await?;
+        for _ in 0..3 {
+            let response = observer.register(&data_path, &[7]).await?;
+            assert!(response.get_observe_value().is_some());
+        }
+
+        request(Method::Put, &data_path, b"1", None).await?;
+        assert_eq!(observer.receive_all().await?, vec![vec![7]], "re-registrations must not multiply notifications");
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_deregistration_removes_only_matching_token() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-tokens-test", |collection| async move {
+        let data_path = create_topic(&collection, "tokens", "0").await?;
+
+        // One endpoint with two independent observations
+        let observer = Observer::new().await?;
+        observer.register(&data_path, &[1]).await?;
+        observer.register(&data_path, &[2]).await?;
+
+        request(Method::Put, &data_path, b"1", None).await?;
+        assert_eq!(observer.receive_all().await?, vec![vec![1], vec![2]]);
+
+        let response = observer.deregister(&data_path, &[1]).await?;
+        assert_eq!(response.payload, b"Unsubscribed successfully");
+        let response = observer.deregister(&data_path, &[3]).await?;
+        assert_eq!(response.payload, b"Subscriber not found");
+
+        request(Method::Put, &data_path, b"2", None).await?;
+        assert_eq!(observer.receive_all().await?, vec![vec![2]]);
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_full_topic_refuses_new_observers() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-max-subscribers-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"crowded\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"crowded\", \"max-subscribers\": 1, \"initialize\": \"0\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let data_path = format!("{}/data/crowded", collection);
+
+        let first = Observer::new().await?;
+        let response = first.register(&data_path, &[1]).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        assert!(response.get_observe_value().is_some());
+
+        let second = Observer::new().await?;
+        let response = second.register(&data_path, &[2]).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::ServiceUnavailable));
+        assert!(response.get_observe_value().is_none(), "refused observer was told it is observing");
+        assert!(!response.payload.is_empty(), "error response without diagnostic payload");
+
+        // The first observer can still refresh its registration and keeps being notified
+        let response = first.register(&data_path, &[1]).await?;
+        assert!(response.get_observe_value().is_some());
+        request(Method::Put, &data_path, b"1", None).await?;
+        assert_eq!(first.receive_all().await?, vec![vec![1]]);
+        assert_eq!(second.receive_all().await?, Vec::<Vec<u8>>::new());
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_stale_data_is_not_notified() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-stale-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"fading\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"fading\", \"observer-check\": 1, \"initialize\": \"0\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let data_path = format!("{}/data/fading", collection);
+
+        let mut publication = new_request(Method::Put, &data_path, b"fresh", None);
+        publication.message.add_option_as(CoapOption::MaxAge, OptionValueU32(2));
+        send(publication).await?;
+        let published = Instant::now();
+        let observer = Observer::new().await?;
+        observer.observe(&data_path, &["pmax=1"], &[1], 0).await?;
+
+        // Observer checks and pmax send the data while it is fresh
+        while let Some(notification) = observer.receive_within(Duration::from_millis(2500).saturating_sub(published.elapsed())).await? {
+            if notification.header.get_type() == MessageType::Confirmable {
+                observer.answer(&notification, MessageType::Acknowledgement).await?;
+            }
+        }
+
+        let stale = observer.receive_within(Duration::from_secs(2)).await?;
+        assert!(stale.is_none(), "stale data was notified: {:?}", stale.map(|notification| notification.payload));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_collection_deletion_ends_observations() -> Result<(), Box<dyn Error>> {
+    let response = request(Method::Post, "", b"{\"collection-name\": \"observe-collection-test\"}", None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+    let data_path = create_topic("observe-collection-test", "site", "0").await?;
+    let observer = Observer::new().await?;
+    observer.register(&data_path, &[9]).await?;
+
+    let response = request(Method::Delete, "observe-collection-test", b"", None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::Deleted));
+    let (notification, _) = observer.receive().await?;
+    assert_eq!(notification.header.code, MessageClass::Response(ResponseType::NotFound));
+    assert_eq!(notification.get_token(), [9]);
+    assert_eq!(notification.payload, b"Topic collection deleted");
+
+    // The topics went with the collection
+    let response = request(Method::Get, &data_path, b"", None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::NotFound));
+    let response = request(Method::Delete, "observe-collection-test", b"", None).await?;
+    assert_eq!(response.header.code, MessageClass::Response(ResponseType::NotFound));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_topic_deletion_ends_observations() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-topic-deletion-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"valve\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"valve\", \"initialize\": \"open\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let topic_path = response.get_option(CoapOption::LocationPath).expect("no Location-Path")
+            .iter().map(|segment| String::from_utf8_lossy(segment).into_owned()).collect::<Vec<_>>().join("/");
+        let data_path = format!("{}/data/valve", collection);
+        let observer = Observer::new().await?;
+        observer.register(&data_path, &[4]).await?;
+
+        let response = request(Method::Delete, &topic_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Deleted));
+        let (notification, _) = observer.receive().await?;
+        assert_eq!(notification.header.code, MessageClass::Response(ResponseType::NotFound));
+        assert_eq!(notification.get_token(), [4]);
+        assert_eq!(notification.payload, b"Topic deleted");
+
+        // The observation went with the topic, a topic created again at the same data uri doesn't notify it
+        let configuration = b"{\"topic-name\": \"valve\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"valve\", \"initialize\": \"open\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let response = request(Method::Put, &data_path, b"closed", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Changed));
+        assert!(observer.receive_within(Duration::from_millis(300)).await?.is_none(), "notified after the topic was deleted");
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_observer_check_probes_from_broker_socket() -> Result<(), Box<dyn Error>> {
+    with_collection("observe-check-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"probed\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"probed\", \"observer-check\": 1, \"initialize\": \"0\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let data_path = format!("{}/data/probed", collection);
+
+        let observer = Observer::new().await?;
+        let response = observer.register(&data_path, &[3]).await?;
+        let registered = response.get_observe_value().expect("no Observe option")?;
+
+        // The probe is a confirmable notification of the observation, numbered after the registration
+        let (probe, src) = observer.receive().await?;
+        assert_eq!(src, BROKER_ADDR.parse::<SocketAddr>()?, "probe didn't come from the broker's listening socket");
+        assert_eq!(probe.header.get_type(), MessageType::Confirmable);
+        assert_eq!(probe.get_token(), [3]);
+        assert_eq!(probe.payload, b"0");
+        assert!(probe.get_observe_value().expect("no Observe option")? > registered);
+
+        // A reset ends the observation
+        observer.answer(&probe, MessageType::Reset).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let response = observer.deregister(&data_path, &[3]).await?;
+        assert_eq!(response.payload, b"Subscriber not found");
+        Ok(())
+    }).await
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use coap_lite::{CoapOption, MessageClass, RequestType as Method, ResponseType};
+use coap_lite::option_value::OptionValueU16;
+use common::content_format::{CORE_PUBSUB_CBOR_CONTENT_FORMAT, JSON_CONTENT_FORMAT};
+use common::{create_topic, new_request, request, send, with_collection, Observer};
+use std::error::Error;
+
+#[tokio::test]
+async fn test_initial_value_fully_creates_topic() -> Result<(), Box<dyn Error>> {
+    with_collection("initial-value-test", |collection| async move {
+        let configuration = b"{\"topic-name\": \"thermometer\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"thermometer\", \"initialize\": \"21.5\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let created: serde_json::Value = serde_json::from_slice(&response.payload)?;
+        assert_eq!(created["topic-state"], "fully-created");
+        assert!(created.get("initialize").is_none());
+
+        let response = request(Method::Get, &format!("{}/data/thermometer", collection), b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        assert_eq!(response.payload, b"21.5");
+
+        // Without an initial value the topic waits for its first publication
+        let configuration = b"{\"topic-name\": \"hygrometer\", \"resource-type\": \"core.ps.conf\", \"topic-data\": \"hygrometer\"}";
+        let response = request(Method::Post, &collection, configuration, None).await?;
+        let created: serde_json::Value = serde_json::from_slice(&response.payload)?;
+        assert_eq!(created["topic-state"], "half-created");
+
+        let response = request(Method::Get, &format!("{}/data/hygrometer", collection), b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::NotFound));
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_initial_value_in_cbor_creation() -> Result<(), Box<dyn Error>> {
+    with_collection("initial-cbor-test", |collection| async move {
+        // {0: "door", 1: "door", 2: "core.ps.conf", 8: h'01'} in application/core-pubsub+cbor
+        let mut configuration = vec![0xa4, 0x00, 0x64];
+        configuration.extend_from_slice(b"door");
+        configuration.extend_from_slice(&[0x01, 0x64]);
+        configuration.extend_from_slice(b"door");
+        configuration.extend_from_slice(&[0x02, 0x6c]);
+        configuration.extend_from_slice(b"core.ps.conf");
+        configuration.extend_from_slice(&[0x08, 0x41, 0x01]);
+
+        let mut creation = new_request(Method::Post, &collection, &configuration, Some(CORE_PUBSUB_CBOR_CONTENT_FORMAT));
+        creation.message.add_option_as(CoapOption::Accept, OptionValueU16(JSON_CONTENT_FORMAT));
+        let response = send(creation).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let created: serde_json::Value = serde_json::from_slice(&response.payload)?;
+        assert_eq!(created["topic-state"], "fully-created");
+
+        let response = request(Method::Get, &format!("{}/data/door", collection), b"", None).await?;
+        assert_eq!(response.payload, [0x01]);
+        Ok(())
+    }).await
+}
+
+#[tokio::test]
+async fn test_data_deletion_returns_topic_to_half_created() -> Result<(), Box<dyn Error>> {
+    with_collection("data-deletion-test", |collection| async move {
+        let data_path = create_topic(&collection, "barometer", "1013").await?;
+
+        let response = request(Method::Delete, &data_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Deleted));
+
+        // Without data the topic can neither be read nor observed
+        let response = request(Method::Get, &data_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::NotFound));
+        let observer = Observer::new().await?;
+        let response = observer.register(&data_path, &[1]).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::NotFound));
+
+        // The next publication creates the data again
+        let response = request(Method::Put, &data_path, b"1009", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Created));
+        let response = request(Method::Get, &data_path, b"", None).await?;
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        assert_eq!(response.payload, b"1009");
+        Ok(())
+    }).await
+}